@@ -1,15 +1,17 @@
+use std::str::FromStr;
 use std::sync::Once;
 
 use anyhow::{bail, Result};
-use flatgeobuf::{FgbFeature, GeozeroGeometry, HttpFgbReader};
-use geo::{Line, LineString, Polygon, Rect};
-use geojson::{de::deserialize_geometry, Feature, GeoJson, Geometry};
+use geo::{LineString, Point, Polygon};
+use geojson::{de::deserialize_geometry, Feature, FeatureCollection, GeoJson, Geometry};
 
 use serde::Deserialize;
 use wasm_bindgen::prelude::*;
 
 use utils::Mercator;
-use widths::Timer;
+use widths::{
+    read_nearby_polygons_http, Class, Constraint, Junctions, Overrides, Perpendicular, Timer,
+};
 
 mod render;
 
@@ -17,6 +19,8 @@ static START: Once = Once::new();
 
 struct Features {
     features: Vec<Feature>,
+    /// The URLs of the constraint datasets, indexed by `Constraint::source`
+    sources: Vec<String>,
 }
 
 impl widths::Output for Features {
@@ -24,19 +28,47 @@ impl widths::Output for Features {
         self.features
             .push(Feature::from(Geometry::from(&mercator.to_wgs84(polygon))));
     }
-    fn perp_line(&mut self, mercator: &Mercator, line: Line, width: f64) {
-        let mut f = Feature::from(Geometry::from(&mercator.to_wgs84(&line)));
-        f.set_property("width", width);
+    fn perp_line(&mut self, mercator: &Mercator, perp: Perpendicular) {
+        let mut f = Feature::from(Geometry::from(&mercator.to_wgs84(&perp.line)));
+        f.set_property("width", perp.width);
+        f.set_property("near_junction", perp.near_junction);
+        for (end, hit) in ["start", "end"].into_iter().zip(perp.hits) {
+            if let Some(hit) = hit {
+                let edge = mercator.to_wgs84(&hit.edge);
+                f.set_property(format!("{end}_source"), self.sources[hit.source].clone());
+                if let Some(id) = hit.id {
+                    f.set_property(format!("{end}_polygon"), id);
+                }
+                f.set_property(
+                    format!("{end}_edge"),
+                    serde_json::json!([[edge.start.x, edge.start.y], [edge.end.x, edge.end.y]]),
+                );
+            }
+        }
+        if let Some(width) = perp.legal_width {
+            f.set_property("legal_width", width);
+            f.set_property("beyond_adopted", perp.beyond_adopted);
+        }
+        for (class, width) in perp.class_widths {
+            f.set_property(format!("width_{}", class.name()), width);
+        }
         self.features.push(f);
     }
 }
 
 /// Takes GeoJSON with one LineString, and returns a FeatureCollection of all negative space
-/// polygons in the polygon.
+/// polygons in the polygon. If `highway_boundaries_url` points to a FlatGeobuf file of adopted
+/// highway land, perpendiculars also get the legal width. Constraints are read from every URL in
+/// `source_urls`, or the default `out.fgb` if it's empty. `overrides` is an optional GeoJSON
+/// FeatureCollection of polygons drawn by the user, each with an `override` property of `add` or
+/// `remove`, to add missing obstacles or remove wrong ones.
 #[wasm_bindgen(js_name = getNegativeSpace)]
 pub async fn get_negative_space(
     input: String,
     progress_cb: Option<js_sys::Function>,
+    highway_boundaries_url: Option<String>,
+    mut source_urls: Vec<String>,
+    overrides: Option<String>,
 ) -> Result<String, JsValue> {
     // Panics shouldn't happen, but if they do, console.log them.
     console_error_panic_hook::set_once();
@@ -54,19 +86,68 @@ pub async fn get_negative_space(
     let project_away_meters = 50.0;
 
     let bbox = widths::bbox(&input_route, project_away_meters);
-    timer.step("Downloading nearby polygons");
-    let url = "http://localhost:5173/will-it-fit/out.fgb";
-    let polygons = read_nearby_polygons(bbox, url).await.map_err(err_to_js)?;
+    if source_urls.is_empty() {
+        source_urls.push("http://localhost:5173/will-it-fit/out.fgb".to_string());
+    }
+    let mut polygons = Vec::new();
+    for (source, url) in source_urls.iter().enumerate() {
+        timer.step(format!("Downloading nearby polygons from {url}"));
+        polygons.extend(
+            read_nearby_polygons_http(bbox, url, source)
+                .await
+                .map_err(err_to_js)?,
+        );
+    }
+    let highway_boundaries = match highway_boundaries_url {
+        Some(url) => {
+            timer.step("Downloading nearby highway boundaries");
+            read_nearby_polygons_http(bbox, &url, 0)
+                .await
+                .map_err(err_to_js)?
+                .into_iter()
+                .map(|c| c.polygon)
+                .collect()
+        }
+        None => Vec::new(),
+    };
+
+    // Added obstacles get their own source, after all the datasets
+    let overrides = match overrides {
+        Some(gj) => parse_overrides(&gj, source_urls.len()).map_err(err_to_js)?,
+        None => Overrides::default(),
+    };
+    source_urls.push("overrides".to_string());
+
+    // We don't know about other streets here, but routes are drawn between junctions, so flag
+    // perpendiculars near either end
+    let junctions = Junctions {
+        points: vec![
+            Point::from(input_route.0[0]),
+            Point::from(*input_route.0.last().unwrap()),
+        ],
+        side_streets: Vec::new(),
+        distance_meters: 10.0,
+        exclude: false,
+        trim_at_side_streets: false,
+        mouth_meters: 0.0,
+    };
 
     let mut out = Features {
         features: Vec::new(),
+        sources: source_urls,
     };
     widths::calculate(
         &input_route,
         polygons,
+        widths::Options {
+            mode: widths::Mode::NegativeSpace,
+            overrides: &overrides,
+            highway_boundaries,
+            junctions: &junctions,
+            step_size_meters,
+            project_away_meters,
+        },
         timer,
-        step_size_meters,
-        project_away_meters,
         &mut out,
     );
 
@@ -98,28 +179,42 @@ struct Input {
     geometry: LineString,
 }
 
-fn err_to_js<E: std::fmt::Display>(err: E) -> JsValue {
-    JsValue::from_str(&err.to_string())
-}
-
-async fn read_nearby_polygons(bbox: Rect, url: &str) -> Result<Vec<Polygon>> {
-    let mut fgb = HttpFgbReader::open(url)
-        .await?
-        .select_bbox(bbox.min().x, bbox.min().y, bbox.max().x, bbox.max().y)
-        .await?;
-
-    let mut polygons = Vec::new();
-    while let Some(feature) = fgb.next().await? {
-        polygons.push(get_polygon(feature)?);
+fn parse_overrides(gj: &str, source: usize) -> Result<Overrides> {
+    let mut overrides = Overrides::default();
+    for (idx, f) in FeatureCollection::from_str(gj)?
+        .features
+        .into_iter()
+        .enumerate()
+    {
+        let kind = f
+            .property("override")
+            .and_then(|x| x.as_str())
+            .unwrap_or_default()
+            .to_string();
+        let class = f
+            .property("class")
+            .and_then(|x| x.as_str())
+            .map(Class::parse)
+            .unwrap_or(Class::Other);
+        let Some(geometry) = f.geometry else {
+            bail!("Override without geometry");
+        };
+        let polygon: Polygon = geometry.try_into()?;
+        match kind.as_str() {
+            // The position among the drawn features identifies added obstacles
+            "add" => overrides.add.push(Constraint {
+                polygon,
+                class,
+                source,
+                id: Some(idx.to_string()),
+            }),
+            "remove" => overrides.remove.push(polygon),
+            _ => bail!("Override must be add or remove, not {kind:?}"),
+        }
     }
-    Ok(polygons)
+    Ok(overrides)
 }
 
-fn get_polygon(f: &FgbFeature) -> Result<Polygon> {
-    let mut p = geozero::geo_types::GeoWriter::new();
-    f.process_geom(&mut p)?;
-    match p.take_geometry().unwrap() {
-        geo::Geometry::Polygon(p) => Ok(p),
-        _ => bail!("Wrong type in fgb"),
-    }
+fn err_to_js<E: std::fmt::Display>(err: E) -> JsValue {
+    JsValue::from_str(&err.to_string())
 }
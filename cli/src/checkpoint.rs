@@ -0,0 +1,75 @@
+use std::collections::HashSet;
+
+use anyhow::{bail, Result};
+use progress_log::ProgressLog;
+
+/// Records which groups of ways have been finished, so a long run can resume after a crash. After
+/// the header, each line has a finished group's index and the length of the output file after
+/// writing it.
+pub struct Checkpoint {
+    log: ProgressLog,
+    done: HashSet<usize>,
+    output_len: u64,
+}
+
+impl Checkpoint {
+    /// Doesn't record anything
+    pub fn none() -> Self {
+        Self {
+            log: ProgressLog::none(),
+            done: HashSet::new(),
+            output_len: 0,
+        }
+    }
+
+    /// Starts a new checkpoint file, or if `resume` is true, continues an existing one with the
+    /// same `header`
+    pub fn open(path: &str, header: &str, resume: bool) -> Result<Self> {
+        if !resume {
+            return Ok(Self {
+                log: ProgressLog::create(path, header)?,
+                done: HashSet::new(),
+                output_len: 0,
+            });
+        }
+
+        let (log, records) = ProgressLog::resume(path, header)?;
+        let mut done = HashSet::new();
+        let mut output_len = 0;
+        for record in records {
+            let Some((idx, len)) = record
+                .split_once(' ')
+                .and_then(|(idx, len)| Some((idx.parse::<usize>().ok()?, len.parse().ok()?)))
+            else {
+                bail!("Bad line in {path}: {record}");
+            };
+            done.insert(idx);
+            output_len = len;
+        }
+        println!("Resuming from {path}, {} groups already done", done.len());
+
+        Ok(Self {
+            log,
+            done,
+            output_len,
+        })
+    }
+
+    pub fn is_done(&self, idx: usize) -> bool {
+        self.done.contains(&idx)
+    }
+
+    /// How long the output file was after the last finished group. Anything after that is from
+    /// an unfinished group and should be discarded when resuming.
+    pub fn output_len(&self) -> u64 {
+        self.output_len
+    }
+
+    /// Only call this after all output for the group has been flushed. `output_len` is the length
+    /// of the output file at that point.
+    pub fn mark_done(&mut self, idx: usize, output_len: u64) -> Result<()> {
+        self.done.insert(idx);
+        self.output_len = output_len;
+        self.log.append(&format!("{idx} {output_len}"))
+    }
+}
@@ -0,0 +1,287 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use anyhow::{bail, Result};
+use flatgeobuf::{ColumnType, FallibleStreamingIterator, FgbReader, GeozeroGeometry};
+use geo::{LineString, MultiLineString, Point};
+use geozero::{ColumnValue, FeatureProperties, PropertyProcessor};
+use serde_json::{Map, Value};
+use utils::osm2graph::Graph;
+use utils::Tags;
+
+use crate::{output, parallel};
+
+/// Something to measure the width along
+pub struct Street {
+    /// Perpendiculars are measured along this, in WGS84
+    pub route: LineString,
+    /// Everything this street represents, in WGS84
+    pub geometry: MultiLineString,
+    /// Properties to copy to the output
+    pub properties: Map<String, Value>,
+    /// OSM ways making up this street, if any
+    pub way_ids: Vec<i64>,
+    /// Where other streets meet this one, in WGS84
+    pub junction_points: Vec<Point>,
+    /// The other streets meeting this one, in WGS84
+    pub side_streets: Vec<LineString>,
+}
+
+pub enum Input {
+    /// Groups of parallel edges are measured together
+    Osm {
+        graph: Graph,
+        groups: Vec<Vec<usize>>,
+    },
+    /// LineStrings with properties from GeoJSON or FlatGeobuf
+    Routes(Vec<(LineString, Map<String, Value>)>),
+}
+
+impl Input {
+    pub fn load(path: &str, max_parallel_distance_meters: f64) -> Result<Self> {
+        if path.ends_with(".osm.pbf") || path.ends_with(".osm.xml") {
+            let graph = Graph::new(
+                &std::fs::read(path)?,
+                keep_edge,
+                &mut utils::osm2graph::NullReader,
+            )?;
+
+            // Dual carriageways and separately mapped footways or cycleways share one corridor.
+            // Measure each group of parallel ways once, so the same building-to-building width
+            // isn't reported multiple times.
+            let groups = parallel::group_parallel_edges(&graph, max_parallel_distance_meters);
+            println!(
+                "Found {} groups of parallel ways among {} edges",
+                groups.len(),
+                graph.edges.len()
+            );
+            Ok(Self::Osm { graph, groups })
+        } else if path.ends_with(".geojson") || path.ends_with(".json") {
+            Ok(Self::Routes(read_geojson_routes(path)?))
+        } else if path.ends_with(".fgb") {
+            Ok(Self::Routes(read_fgb_routes(path)?))
+        } else {
+            bail!("Unknown input format for {path}");
+        }
+    }
+
+    pub fn num_streets(&self) -> usize {
+        match self {
+            Self::Osm { groups, .. } => groups.len(),
+            Self::Routes(routes) => routes.len(),
+        }
+    }
+
+    pub fn street(&self, idx: usize) -> Street {
+        match self {
+            Self::Osm { graph, groups } => osm_street(graph, &groups[idx]),
+            Self::Routes(routes) => {
+                let (route, properties) = routes[idx].clone();
+                // Assume the route starts and ends at junctions
+                let junction_points = vec![
+                    Point::from(route.0[0]),
+                    Point::from(*route.0.last().unwrap()),
+                ];
+                Street {
+                    geometry: MultiLineString::new(vec![route.clone()]),
+                    route,
+                    properties,
+                    way_ids: Vec::new(),
+                    junction_points,
+                    side_streets: Vec::new(),
+                }
+            }
+        }
+    }
+
+    /// Describes the input properties, to declare in the output schema. Columns with a mix of
+    /// integers and decimals become Double, and any other mix becomes Json.
+    pub fn property_columns(&self) -> Vec<(String, ColumnType)> {
+        let mut columns: Vec<(String, ColumnType)> = Vec::new();
+        let Self::Routes(routes) = self else {
+            return columns;
+        };
+        for (_, properties) in routes {
+            for (key, value) in properties {
+                let column_type = match value {
+                    Value::Null => continue,
+                    Value::Bool(_) => ColumnType::Bool,
+                    Value::Number(x) if x.is_i64() => ColumnType::Long,
+                    Value::Number(_) => ColumnType::Double,
+                    Value::String(_) => ColumnType::String,
+                    Value::Array(_) | Value::Object(_) => ColumnType::Json,
+                };
+                match columns.iter_mut().find(|(name, _)| name == key) {
+                    Some((_, existing)) => *existing = widen(*existing, column_type),
+                    None => columns.push((key.clone(), column_type)),
+                }
+            }
+        }
+        columns
+    }
+}
+
+// The narrowest column type able to hold values of both types
+fn widen(t1: ColumnType, t2: ColumnType) -> ColumnType {
+    match (t1, t2) {
+        _ if t1 == t2 => t1,
+        (ColumnType::Long, ColumnType::Double) | (ColumnType::Double, ColumnType::Long) => {
+            ColumnType::Double
+        }
+        _ => ColumnType::Json,
+    }
+}
+
+fn keep_edge(tags: &Tags) -> bool {
+    if !tags.has("highway") || tags.is("highway", "proposed") || tags.is("area", "yes") {
+        return false;
+    }
+    true
+}
+
+fn osm_street(graph: &Graph, group: &[usize]) -> Street {
+    // The perpendiculars from any member cross the whole corridor, so measure once along a route
+    // spanning all of them
+    let members: Vec<&LineString> = group.iter().map(|e| &graph.edges[*e].linestring).collect();
+    let route = parallel::corridor_route(&members);
+
+    let way_ids: Vec<i64> = group.iter().map(|e| graph.edges[*e].osm_way.0).collect();
+    let mut properties = Map::new();
+    properties.insert("way_ids".to_string(), Value::from(way_ids.clone()));
+    properties.insert("num_ways".to_string(), Value::from(group.len()));
+
+    // Find all the junctions at either end of the ways in the group
+    let mut junction_points = Vec::new();
+    let mut side_streets = Vec::new();
+    for e in group {
+        let edge = &graph.edges[*e];
+        for i in [edge.src, edge.dst] {
+            let intersection = &graph.intersections[i.0];
+            // Intersections with only two edges are just places where a way is split
+            if intersection.edges.len() <= 2 {
+                continue;
+            }
+            junction_points.push(graph.mercator.to_wgs84(&intersection.point));
+            for other in &intersection.edges {
+                // The route's own ways continuing through the junction aren't side streets
+                if !group.contains(&other.0) && !way_ids.contains(&graph.edges[other.0].osm_way.0) {
+                    side_streets.push(graph.mercator.to_wgs84(&graph.edges[other.0].linestring));
+                }
+            }
+        }
+    }
+
+    Street {
+        route: graph.mercator.to_wgs84(&route),
+        geometry: MultiLineString::new(
+            group
+                .iter()
+                .map(|e| graph.mercator.to_wgs84(&graph.edges[*e].linestring))
+                .collect(),
+        ),
+        properties,
+        way_ids,
+        junction_points,
+        side_streets,
+    }
+}
+
+fn read_geojson_routes(path: &str) -> Result<Vec<(LineString, Map<String, Value>)>> {
+    println!("Reading {path}");
+    let mut routes = Vec::new();
+    let reader = geojson::FeatureReader::from_reader(BufReader::new(File::open(path)?));
+    for feature in reader.features() {
+        let feature = feature?;
+        let properties = feature.properties.clone().unwrap_or_default();
+        let Some(geometry) = feature.geometry else {
+            continue;
+        };
+        add_routes(&mut routes, geometry.try_into()?, properties)?;
+    }
+    Ok(routes)
+}
+
+fn read_fgb_routes(path: &str) -> Result<Vec<(LineString, Map<String, Value>)>> {
+    println!("Reading {path}");
+    let mut routes = Vec::new();
+    let mut fgb = FgbReader::open(BufReader::new(File::open(path)?))?.select_all()?;
+    while let Some(feature) = fgb.next()? {
+        let mut properties = JsonProperties(Map::new());
+        feature.process_properties(&mut properties)?;
+
+        let mut geometry = geozero::geo_types::GeoWriter::new();
+        feature.process_geom(&mut geometry)?;
+        // Like GeoJSON input, skip features without a geometry
+        let Some(geometry) = geometry.take_geometry() else {
+            continue;
+        };
+        add_routes(&mut routes, geometry, properties.0)?;
+    }
+    Ok(routes)
+}
+
+// Each part of a MultiLineString is measured separately, but keeps the same properties. Parts
+// without at least two points can't be measured, so they're skipped.
+fn add_routes(
+    routes: &mut Vec<(LineString, Map<String, Value>)>,
+    geometry: geo::Geometry,
+    properties: Map<String, Value>,
+) -> Result<()> {
+    let linestrings = match geometry {
+        geo::Geometry::LineString(ls) => vec![ls],
+        geo::Geometry::MultiLineString(mls) => mls.0,
+        _ => bail!("Input routes must be LineStrings or MultiLineStrings"),
+    };
+    let properties = rename_clashing_properties(properties);
+    for ls in linestrings {
+        if ls.0.len() >= 2 {
+            routes.push((ls, properties.clone()));
+        }
+    }
+    Ok(())
+}
+
+// Input properties named like one the CLI writes, such as `width`, get an `input_` prefix, so
+// both are kept
+fn rename_clashing_properties(properties: Map<String, Value>) -> Map<String, Value> {
+    properties
+        .into_iter()
+        .map(|(key, value)| {
+            if output::is_own_column(&key) {
+                (format!("input_{key}"), value)
+            } else {
+                (key, value)
+            }
+        })
+        .collect()
+}
+
+struct JsonProperties(Map<String, Value>);
+
+impl PropertyProcessor for JsonProperties {
+    fn property(
+        &mut self,
+        _: usize,
+        name: &str,
+        value: &ColumnValue,
+    ) -> geozero::error::Result<bool> {
+        let value = match value {
+            ColumnValue::Byte(x) => Value::from(*x),
+            ColumnValue::UByte(x) => Value::from(*x),
+            ColumnValue::Bool(x) => Value::from(*x),
+            ColumnValue::Short(x) => Value::from(*x),
+            ColumnValue::UShort(x) => Value::from(*x),
+            ColumnValue::Int(x) => Value::from(*x),
+            ColumnValue::UInt(x) => Value::from(*x),
+            ColumnValue::Long(x) => Value::from(*x),
+            ColumnValue::ULong(x) => Value::from(*x),
+            ColumnValue::Float(x) => Value::from(*x),
+            ColumnValue::Double(x) => Value::from(*x),
+            ColumnValue::String(x) | ColumnValue::DateTime(x) => Value::from(*x),
+            ColumnValue::Json(x) => serde_json::from_str(x).unwrap_or_else(|_| Value::from(*x)),
+            ColumnValue::Binary(_) => return Ok(false),
+        };
+        self.0.insert(name.to_string(), value);
+        Ok(false)
+    }
+}
@@ -1,53 +1,171 @@
-use std::fs::File;
-use std::io::BufReader;
+use std::collections::HashSet;
 
 use anyhow::{bail, Result};
-use flatgeobuf::{FallibleStreamingIterator, FgbFeature, FgbReader, GeozeroGeometry};
-use geo::{Line, Polygon, Rect};
-use geojson::{Feature, GeoJson, Geometry};
-use utils::{Mercator, Tags};
+use clap::Parser;
+use geo::{Coord, Intersects, Rect};
+use geojson::{Feature, Geometry};
+use serde_json::Value;
 
-use widths::Timer;
+use widths::{read_nearby_polygons, Junctions, Timer};
+
+mod checkpoint;
+mod input;
+mod output;
+mod parallel;
+
+#[derive(Parser)]
+struct Args {
+    /// A .osm.pbf or .osm.xml input, or a .geojson or .fgb file with LineStrings to measure.
+    /// Properties of the LineStrings are copied to the output.
+    input: String,
+
+    /// Where to write the results
+    #[arg(long, default_value = "out.geojson")]
+    output: String,
+
+    /// The format of the output file
+    #[arg(long, value_enum, default_value_t = output::Format::Geojson)]
+    format: output::Format,
+
+    /// Perpendiculars within this distance of a junction are flagged as near_junction
+    #[arg(long, default_value_t = 10.0)]
+    junction_distance_meters: f64,
+
+    /// Skip perpendiculars near junctions, instead of just flagging them
+    #[arg(long)]
+    exclude_near_junctions: bool,
+
+    /// Don't cut perpendiculars near junctions short where they cross a side street
+    #[arg(long)]
+    no_trim_at_side_streets: bool,
+
+    /// Roughly half the carriageway width. Perpendiculars near junctions are cut where they cross
+    /// a line this far into a side street, instead of running down it.
+    #[arg(long, default_value_t = 4.0)]
+    side_street_mouth_meters: f64,
+
+    /// Record finished streets in this file, so an interrupted run can be resumed
+    #[arg(long)]
+    checkpoint: Option<String>,
+
+    /// Skip streets already recorded in the checkpoint file, and append to the output. Only
+    /// works with geojsonseq output.
+    #[arg(long, requires = "checkpoint")]
+    resume: bool,
+
+    /// Only process streets intersecting this WGS84 bounding box, given as
+    /// `min_lon,min_lat,max_lon,max_lat`
+    #[arg(long, value_delimiter = ',', allow_hyphen_values = true)]
+    bbox: Option<Vec<f64>>,
+
+    /// Only process streets including one of the OSM way IDs in this file, one per line
+    #[arg(long)]
+    way_ids_file: Option<String>,
+
+    /// FlatGeobuf files with polygons constraining road width, comma-separated. All of them are
+    /// used, and each perpendicular records which file its ends came from.
+    #[arg(long, value_delimiter = ',', default_value = "../web/public/out.fgb")]
+    sources: Vec<String>,
+
+    /// The polygons represent road space, like `fix_osmm --positive-space` output, instead of
+    /// obstacles. Perpendiculars are clipped to the road space containing the street.
+    #[arg(long)]
+    positive_space: bool,
+
+    /// A FlatGeobuf file with polygons of adopted highway land. If given, the legal width is
+    /// reported alongside the physical width, flagging places where physical space extends
+    /// beyond it.
+    #[arg(long)]
+    highway_boundaries: Option<String>,
+}
 
 fn main() -> Result<()> {
     env_logger::init();
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        println!("Call with a .osm.pbf or .osm.xml input");
-        std::process::exit(1);
-    }
+    let args = Args::parse();
 
-    let graph = utils::osm2graph::Graph::new(
-        &std::fs::read(&args[1])?,
-        keep_edge,
-        &mut utils::osm2graph::NullReader,
-    )?;
+    let step_size_meters = 5.0;
+    let project_away_meters = 50.0;
+    let max_parallel_distance_meters = 20.0;
+    let input = input::Input::load(&args.input, max_parallel_distance_meters)?;
+    let mode = if args.positive_space {
+        widths::Mode::PositiveSpace
+    } else {
+        widths::Mode::NegativeSpace
+    };
+    // Overrides are only drawn in the web app
+    let overrides = widths::Overrides::default();
 
-    let mut out = Features {
-        features: Vec::new(),
-        widths: Vec::new(),
+    let mut checkpoint = if let Some(ref path) = args.checkpoint {
+        // Other formats can't be appended to, so progress could never be resumed
+        if !matches!(args.format, output::Format::Geojsonseq) {
+            bail!("--checkpoint only works with --format geojsonseq");
+        }
+        checkpoint::Checkpoint::open(path, &checkpoint_header(&args, &input), args.resume)?
+    } else {
+        checkpoint::Checkpoint::none()
     };
 
-    for (idx, edge) in graph.edges.iter().enumerate() {
-        println!("Working on edge {}/{}", idx, graph.edges.len());
+    let mut out = output::Writer::new(
+        args.format,
+        &args.output,
+        args.resume.then(|| checkpoint.output_len()),
+        input.property_columns(),
+        args.sources.clone(),
+    )?;
+    let filter = Filter::new(&args)?;
+    if filter.way_ids.is_some() && matches!(input, input::Input::Routes(_)) {
+        bail!("--way-ids-file only works with OSM input");
+    }
+
+    for idx in 0..input.num_streets() {
+        if checkpoint.is_done(idx) {
+            continue;
+        }
+        let street = input.street(idx);
+        if !filter.keep(&street) {
+            continue;
+        }
+        println!("Working on street {}/{}", idx, input.num_streets());
 
-        let edge_wgs84 = graph.mercator.to_wgs84(&edge.linestring);
+        let junctions = Junctions {
+            points: street.junction_points,
+            side_streets: street.side_streets,
+            distance_meters: args.junction_distance_meters,
+            exclude: args.exclude_near_junctions,
+            trim_at_side_streets: !args.no_trim_at_side_streets,
+            mouth_meters: args.side_street_mouth_meters,
+        };
         let mut timer = Timer::new("calculate negative space", None);
-        let step_size_meters = 5.0;
-        let project_away_meters = 50.0;
 
-        let bbox = widths::bbox(&edge_wgs84, project_away_meters);
+        let bbox = widths::bbox(&street.route, project_away_meters);
         timer.step("Downloading nearby polygons");
-        let polygons = read_nearby_polygons(bbox, "../web/public/out.fgb")?;
+        let mut polygons = Vec::new();
+        for (source, path) in args.sources.iter().enumerate() {
+            polygons.extend(read_nearby_polygons(bbox, path, source)?);
+        }
+        let highway_boundaries = match args.highway_boundaries {
+            Some(ref path) => read_nearby_polygons(bbox, path, 0)?
+                .into_iter()
+                .map(|c| c.polygon)
+                .collect(),
+            None => Vec::new(),
+        };
 
         widths::calculate(
-            &edge_wgs84,
+            &street.route,
             polygons,
+            widths::Options {
+                mode,
+                overrides: &overrides,
+                highway_boundaries,
+                junctions: &junctions,
+                step_size_meters,
+                project_away_meters,
+            },
             timer,
-            step_size_meters,
-            project_away_meters,
             &mut out,
         );
+        out.take_error()?;
 
         let mut sum = 0.0;
         let mut min = f64::MAX;
@@ -56,62 +174,105 @@ fn main() -> Result<()> {
             sum += width;
             min = min.min(width);
         }
-        let mut f = Feature::from(Geometry::from(&edge_wgs84));
-        f.set_property("min_width", min);
-        f.set_property("avg_width", sum / (n as f64));
-        out.features.push(f);
+        let mut f = Feature::from(Geometry::from(&street.geometry));
+        f.properties = Some(street.properties);
+        // Short streets might only have perpendiculars near junctions
+        if n == 0 {
+            f.set_property("min_width", Value::Null);
+            f.set_property("avg_width", Value::Null);
+        } else {
+            f.set_property("min_width", min);
+            f.set_property("avg_width", sum / (n as f64));
+        }
+        out.write_feature(f)?;
+
+        if args.checkpoint.is_some() {
+            let output_len = out.output_len()?;
+            checkpoint.mark_done(idx, output_len)?;
+        } else {
+            out.flush()?;
+        }
     }
 
-    Ok(std::fs::write(
-        "out.geojson",
-        serde_json::to_string(&GeoJson::from(out.features))?,
-    )?)
+    out.finish()
 }
 
-fn keep_edge(tags: &Tags) -> bool {
-    if !tags.has("highway") || tags.is("highway", "proposed") || tags.is("area", "yes") {
-        return false;
-    }
-    true
+/// Restricts which streets to process
+/// Identifies everything affecting the results, so a run can't resume with different settings
+fn checkpoint_header(args: &Args, input: &input::Input) -> String {
+    format!(
+        "input={} streets={} sources={:?} positive_space={} highway_boundaries={:?} bbox={:?} \
+         way_ids_file={:?} junction_distance_meters={} exclude_near_junctions={} \
+         no_trim_at_side_streets={} side_street_mouth_meters={}",
+        args.input,
+        input.num_streets(),
+        args.sources,
+        args.positive_space,
+        args.highway_boundaries,
+        args.bbox,
+        args.way_ids_file,
+        args.junction_distance_meters,
+        args.exclude_near_junctions,
+        args.no_trim_at_side_streets,
+        args.side_street_mouth_meters,
+    )
 }
 
-fn read_nearby_polygons(bbox: Rect, path: &str) -> Result<Vec<Polygon>> {
-    // TODO Open once?
-    let mut fgb = FgbReader::open(BufReader::new(File::open(path)?))?.select_bbox(
-        bbox.min().x,
-        bbox.min().y,
-        bbox.max().x,
-        bbox.max().y,
-    )?;
-
-    let mut polygons = Vec::new();
-    while let Some(feature) = fgb.next()? {
-        polygons.push(get_polygon(feature)?);
-    }
-    Ok(polygons)
+struct Filter {
+    bbox: Option<Rect>,
+    way_ids: Option<HashSet<i64>>,
 }
 
-fn get_polygon(f: &FgbFeature) -> Result<Polygon> {
-    let mut p = geozero::geo_types::GeoWriter::new();
-    f.process_geom(&mut p)?;
-    match p.take_geometry().unwrap() {
-        geo::Geometry::Polygon(p) => Ok(p),
-        _ => bail!("Wrong type in fgb"),
-    }
-}
+impl Filter {
+    fn new(args: &Args) -> Result<Self> {
+        let bbox = match args.bbox {
+            Some(ref bbox) => {
+                if bbox.len() != 4 {
+                    bail!("--bbox needs 4 numbers, not {}", bbox.len());
+                }
+                Some(Rect::new(
+                    Coord {
+                        x: bbox[0],
+                        y: bbox[1],
+                    },
+                    Coord {
+                        x: bbox[2],
+                        y: bbox[3],
+                    },
+                ))
+            }
+            None => None,
+        };
 
-struct Features {
-    features: Vec<Feature>,
-    widths: Vec<f64>,
-}
+        let way_ids = match args.way_ids_file {
+            Some(ref path) => {
+                let mut way_ids = HashSet::new();
+                for line in std::fs::read_to_string(path)?.lines() {
+                    let line = line.trim();
+                    if !line.is_empty() {
+                        way_ids.insert(line.parse::<i64>()?);
+                    }
+                }
+                println!("Only processing {} ways from {path}", way_ids.len());
+                Some(way_ids)
+            }
+            None => None,
+        };
 
-impl widths::Output for Features {
-    fn nearby_polygon(&mut self, _: &Mercator, _: &Polygon) {}
-    fn perp_line(&mut self, mercator: &Mercator, line: Line, width: f64) {
-        let mut f = Feature::from(Geometry::from(&mercator.to_wgs84(&line)));
-        f.set_property("width", width);
-        self.features.push(f);
+        Ok(Self { bbox, way_ids })
+    }
 
-        self.widths.push(width);
+    fn keep(&self, street: &input::Street) -> bool {
+        if let Some(ref way_ids) = self.way_ids {
+            if !street.way_ids.iter().any(|id| way_ids.contains(id)) {
+                return false;
+            }
+        }
+        if let Some(ref bbox) = self.bbox {
+            if !bbox.intersects(&street.geometry) {
+                return false;
+            }
+        }
+        true
     }
 }
@@ -0,0 +1,321 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use flatgeobuf::{ColumnType, FgbWriter, GeometryType};
+use geo::Polygon;
+use geojson::{Feature, Geometry};
+use geozero::ColumnValue;
+use serde_json::Value;
+use utils::Mercator;
+use widths::{Class, Perpendicular};
+
+/// Every property the CLI writes, besides per-class widths and those copied from the input.
+/// FlatGeobuf needs the schema up-front.
+const COLUMNS: [(&str, ColumnType); 14] = [
+    ("width", ColumnType::Double),
+    ("near_junction", ColumnType::Bool),
+    ("legal_width", ColumnType::Double),
+    ("beyond_adopted", ColumnType::Bool),
+    ("start_source", ColumnType::String),
+    ("end_source", ColumnType::String),
+    ("start_polygon", ColumnType::String),
+    ("end_polygon", ColumnType::String),
+    ("start_edge", ColumnType::Json),
+    ("end_edge", ColumnType::Json),
+    ("way_ids", ColumnType::Json),
+    ("num_ways", ColumnType::ULong),
+    ("min_width", ColumnType::Double),
+    ("avg_width", ColumnType::Double),
+];
+
+/// True for properties the CLI writes itself, including per-class widths
+pub fn is_own_column(name: &str) -> bool {
+    COLUMNS.iter().any(|(x, _)| *x == name)
+        || Class::ALL
+            .iter()
+            .any(|class| name == format!("width_{}", class.name()))
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// One GeoJSON FeatureCollection
+    Geojson,
+    /// Newline-delimited GeoJSON features
+    Geojsonseq,
+    /// FlatGeobuf with a spatial index
+    Fgb,
+}
+
+/// Writes features to disk as they're produced, instead of holding everything in memory
+pub struct Writer {
+    inner: Inner,
+    /// The names of the constraint datasets, indexed by `Constraint::source`
+    sources: Vec<String>,
+    /// The widths of every perpendicular for the current street, to summarize afterwards
+    pub widths: Vec<f64>,
+    // widths::Output can't fail, so remember the first problem
+    error: Option<anyhow::Error>,
+}
+
+enum Inner {
+    GeoJson {
+        file: BufWriter<File>,
+        any_features: bool,
+    },
+    GeoJsonSeq(BufWriter<File>),
+    // FgbWriter keeps features in a temporary file, and only builds the index and writes the
+    // final file at the end
+    FlatGeobuf {
+        fgb: FgbWriter<'static>,
+        path: String,
+        columns: Vec<(String, ColumnType)>,
+    },
+}
+
+impl Writer {
+    /// When `resume_at` is set, add to an existing file from a previous run, first cutting it back
+    /// to that many bytes to drop anything from an unfinished street. Only newline-delimited
+    /// GeoJSON supports this. `input_columns` describes properties copied from the input, and
+    /// `sources` names the constraint datasets.
+    pub fn new(
+        format: Format,
+        path: &str,
+        resume_at: Option<u64>,
+        input_columns: Vec<(String, ColumnType)>,
+        sources: Vec<String>,
+    ) -> Result<Self> {
+        if resume_at.is_some() && !matches!(format, Format::Geojsonseq) {
+            bail!("Only geojsonseq output can be appended to when resuming");
+        }
+        let inner = match format {
+            Format::Geojson => {
+                let mut file = BufWriter::new(File::create(path)?);
+                write!(file, "{{\"type\":\"FeatureCollection\",\"features\":[")?;
+                Inner::GeoJson {
+                    file,
+                    any_features: false,
+                }
+            }
+            Format::Geojsonseq => {
+                let file = if let Some(len) = resume_at {
+                    let file = OpenOptions::new().append(true).open(path)?;
+                    file.set_len(len)?;
+                    file
+                } else {
+                    File::create(path)?
+                };
+                Inner::GeoJsonSeq(BufWriter::new(file))
+            }
+            Format::Fgb => {
+                let mut columns: Vec<(String, ColumnType)> = COLUMNS
+                    .into_iter()
+                    .map(|(name, column_type)| (name.to_string(), column_type))
+                    .collect();
+                for class in Class::ALL {
+                    columns.push((format!("width_{}", class.name()), ColumnType::Double));
+                }
+                // Input properties clashing with ours were already renamed
+                columns.extend(input_columns);
+
+                let mut fgb = FgbWriter::create("widths", GeometryType::Unknown)?;
+                for (name, column_type) in &columns {
+                    fgb.add_column(name, *column_type, |_, col| {
+                        col.nullable = true;
+                    });
+                }
+                Inner::FlatGeobuf {
+                    fgb,
+                    path: path.to_string(),
+                    columns,
+                }
+            }
+        };
+        Ok(Self {
+            inner,
+            sources,
+            widths: Vec::new(),
+            error: None,
+        })
+    }
+
+    pub fn write_feature(&mut self, f: Feature) -> Result<()> {
+        match self.inner {
+            Inner::GeoJson {
+                ref mut file,
+                ref mut any_features,
+            } => {
+                if *any_features {
+                    write!(file, ",")?;
+                }
+                *any_features = true;
+                writeln!(file)?;
+                serde_json::to_writer(file, &f)?;
+            }
+            Inner::GeoJsonSeq(ref mut file) => {
+                serde_json::to_writer(&mut *file, &f)?;
+                writeln!(file)?;
+            }
+            Inner::FlatGeobuf {
+                ref mut fgb,
+                ref columns,
+                ..
+            } => {
+                let Some(geometry) = f.geometry.clone() else {
+                    bail!("Feature without geometry");
+                };
+                let geometry: geo::Geometry = geometry.try_into()?;
+                let mut result = Ok(());
+                fgb.add_feature_geom(geometry, |feat| {
+                    for (idx, (name, column_type)) in columns.iter().enumerate() {
+                        let Some(value) = f.property(name) else {
+                            continue;
+                        };
+                        if let Err(err) = write_property(feat, idx, name, *column_type, value) {
+                            result = Err(err);
+                        }
+                    }
+                })?;
+                result?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes sure everything written so far is on disk. Only call this between streets.
+    pub fn flush(&mut self) -> Result<()> {
+        match self.inner {
+            Inner::GeoJson { ref mut file, .. } => file.flush()?,
+            Inner::GeoJsonSeq(ref mut file) => file.flush()?,
+            // Nothing useful to do until the end
+            Inner::FlatGeobuf { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Flushes and syncs newline-delimited GeoJSON output, returning its length. Only call this
+    /// between streets.
+    pub fn output_len(&mut self) -> Result<u64> {
+        match self.inner {
+            Inner::GeoJsonSeq(ref mut file) => {
+                file.flush()?;
+                file.get_ref().sync_data()?;
+                Ok(file.get_ref().metadata()?.len())
+            }
+            _ => bail!("Only geojsonseq output can be resumed"),
+        }
+    }
+
+    /// Returns the first error that happened while writing perpendiculars
+    pub fn take_error(&mut self) -> Result<()> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    pub fn finish(self) -> Result<()> {
+        match self.inner {
+            Inner::GeoJson { mut file, .. } => {
+                writeln!(file, "]}}")?;
+                file.flush()?;
+            }
+            Inner::GeoJsonSeq(mut file) => {
+                file.flush()?;
+            }
+            Inner::FlatGeobuf { fgb, path, .. } => {
+                println!("Writing {path}");
+                let mut file = BufWriter::new(File::create(path)?);
+                fgb.write(&mut file)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_property<P: geozero::PropertyProcessor>(
+    out: &mut P,
+    idx: usize,
+    name: &str,
+    column_type: ColumnType,
+    value: &Value,
+) -> Result<()> {
+    if value.is_null() {
+        return Ok(());
+    }
+    match column_type {
+        ColumnType::Bool => {
+            let Some(x) = value.as_bool() else {
+                bail!("{name} isn't a bool: {value}");
+            };
+            out.property(idx, name, &ColumnValue::Bool(x))?;
+        }
+        ColumnType::Long => {
+            let Some(x) = value.as_i64() else {
+                bail!("{name} isn't an integer: {value}");
+            };
+            out.property(idx, name, &ColumnValue::Long(x))?;
+        }
+        ColumnType::ULong => {
+            let Some(x) = value.as_u64() else {
+                bail!("{name} isn't an unsigned integer: {value}");
+            };
+            out.property(idx, name, &ColumnValue::ULong(x))?;
+        }
+        ColumnType::Double => {
+            let Some(x) = value.as_f64() else {
+                bail!("{name} isn't a number: {value}");
+            };
+            out.property(idx, name, &ColumnValue::Double(x))?;
+        }
+        ColumnType::String => {
+            let Some(x) = value.as_str() else {
+                bail!("{name} isn't a string: {value}");
+            };
+            out.property(idx, name, &ColumnValue::String(x))?;
+        }
+        ColumnType::Json => {
+            out.property(idx, name, &ColumnValue::Json(&value.to_string()))?;
+        }
+        _ => bail!("{name} has unsupported column type {column_type:?}"),
+    }
+    Ok(())
+}
+
+impl widths::Output for Writer {
+    fn nearby_polygon(&mut self, _: &Mercator, _: &Polygon) {}
+    fn perp_line(&mut self, mercator: &Mercator, perp: Perpendicular) {
+        let mut f = Feature::from(Geometry::from(&mercator.to_wgs84(&perp.line)));
+        f.set_property("width", perp.width);
+        f.set_property("near_junction", perp.near_junction);
+        for (end, hit) in ["start", "end"].into_iter().zip(perp.hits) {
+            if let Some(hit) = hit {
+                let edge = mercator.to_wgs84(&hit.edge);
+                f.set_property(format!("{end}_source"), self.sources[hit.source].clone());
+                if let Some(id) = hit.id {
+                    f.set_property(format!("{end}_polygon"), id);
+                }
+                f.set_property(
+                    format!("{end}_edge"),
+                    serde_json::json!([[edge.start.x, edge.start.y], [edge.end.x, edge.end.y]]),
+                );
+            }
+        }
+        if let Some(width) = perp.legal_width {
+            f.set_property("legal_width", width);
+            f.set_property("beyond_adopted", perp.beyond_adopted);
+        }
+        for (class, width) in perp.class_widths {
+            f.set_property(format!("width_{}", class.name()), width);
+        }
+        if let Err(err) = self.write_feature(f) {
+            self.error.get_or_insert(err);
+        }
+
+        // Don't let junctions skew the summary for the whole street
+        if !perp.near_junction {
+            self.widths.push(perp.width);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use geo::{Distance, Euclidean, Length, LineInterpolatePoint, LineLocatePoint, LineString, Point};
+use rstar::{primitives::GeomWithData, RTree, RTreeObject, AABB};
+use utils::osm2graph::Graph;
+
+/// Two edges running in roughly the same direction within this angle could be part of the same
+/// corridor
+const MAX_ANGLE_DIFFERENCE_DEGREES: f64 = 20.0;
+
+/// Only extend a corridor route past its ends by more than this
+const MIN_EXTENSION_METERS: f64 = 0.1;
+
+/// Groups edges that run alongside each other in the same corridor, like the two halves of a dual
+/// carriageway or a separately mapped footway or cycleway. Every edge belongs to exactly one
+/// group, every edge in a group is parallel to all the others, and the returned indices are into
+/// `graph.edges`.
+pub fn group_parallel_edges(graph: &Graph, max_distance_meters: f64) -> Vec<Vec<usize>> {
+    let rtree = RTree::bulk_load(
+        graph
+            .edges
+            .iter()
+            .enumerate()
+            .map(|(idx, e)| GeomWithData::new(e.linestring.clone(), idx))
+            .collect(),
+    );
+
+    // Find every parallel pair first, so groups are built in a fixed order
+    let mut pairs = Vec::new();
+    for (idx1, edge) in graph.edges.iter().enumerate() {
+        let envelope = edge.linestring.envelope();
+        // Expand the search to anything within range
+        let search = AABB::from_corners(
+            Point::new(
+                envelope.lower().x() - max_distance_meters,
+                envelope.lower().y() - max_distance_meters,
+            ),
+            Point::new(
+                envelope.upper().x() + max_distance_meters,
+                envelope.upper().y() + max_distance_meters,
+            ),
+        );
+        let mut candidates: Vec<usize> = rtree
+            .locate_in_envelope_intersecting(&search)
+            .map(|obj| obj.data)
+            .filter(|idx2| idx1 < *idx2)
+            .collect();
+        candidates.sort();
+        for idx2 in candidates {
+            if is_parallel(
+                &edge.linestring,
+                &graph.edges[idx2].linestring,
+                max_distance_meters,
+            ) {
+                pairs.push((idx1, idx2));
+            }
+        }
+    }
+
+    // Only merge two groups if every edge in one is parallel to every edge in the other. Otherwise
+    // a sidewalk spanning a junction would join the road segments on either side, and a whole
+    // corridor would chain together end-to-end.
+    let mut group_of: Vec<usize> = (0..graph.edges.len()).collect();
+    let mut groups: Vec<Vec<usize>> = (0..graph.edges.len()).map(|idx| vec![idx]).collect();
+    for (idx1, idx2) in pairs {
+        let (g1, g2) = (group_of[idx1], group_of[idx2]);
+        if g1 == g2 {
+            continue;
+        }
+        let all_parallel = groups[g1].iter().all(|e1| {
+            groups[g2].iter().all(|e2| {
+                is_parallel(
+                    &graph.edges[*e1].linestring,
+                    &graph.edges[*e2].linestring,
+                    max_distance_meters,
+                )
+            })
+        });
+        if !all_parallel {
+            continue;
+        }
+        let (keep, remove) = (g1.min(g2), g1.max(g2));
+        let moved = std::mem::take(&mut groups[remove]);
+        for e in &moved {
+            group_of[*e] = keep;
+        }
+        groups[keep].extend(moved);
+    }
+
+    let mut groups: Vec<Vec<usize>> = groups
+        .into_iter()
+        .filter(|group| !group.is_empty())
+        .map(|mut group| {
+            group.sort();
+            group
+        })
+        .collect();
+    // Keep the output in the same order as the edges
+    groups.sort_by_key(|group| group[0]);
+    groups
+}
+
+/// Makes one route covering the full extent of a group of parallel linestrings, in Mercator. The
+/// longest one is extended straight along its overall direction past either end, as far as any
+/// other member reaches.
+pub fn corridor_route(members: &[&LineString]) -> LineString {
+    let longest = *members
+        .iter()
+        .max_by(|ls1, ls2| {
+            ls1.length::<Euclidean>()
+                .total_cmp(&ls2.length::<Euclidean>())
+        })
+        .unwrap();
+    let start = longest.0[0];
+    let end = *longest.0.last().unwrap();
+    let axis_length = (end - start).x.hypot((end - start).y);
+    if axis_length == 0.0 {
+        return longest.clone();
+    }
+    let dir = (end - start) / axis_length;
+
+    // How far along the axis every member point reaches, relative to the start
+    let mut min = 0.0_f64;
+    let mut max = axis_length;
+    for ls in members {
+        for pt in [ls.0[0], *ls.0.last().unwrap()] {
+            let along = (pt - start).x * dir.x + (pt - start).y * dir.y;
+            min = min.min(along);
+            max = max.max(along);
+        }
+    }
+
+    // Don't add tiny extensions from rounding
+    let mut pts = Vec::new();
+    if min < -MIN_EXTENSION_METERS {
+        pts.push(start + dir * min);
+    }
+    pts.extend(longest.0.iter().cloned());
+    if max > axis_length + MIN_EXTENSION_METERS {
+        pts.push(end + dir * (max - axis_length));
+    }
+    LineString::new(pts)
+}
+
+// Both linestrings must be in Mercator. The shorter one has to point the same way as the longer
+// one and sit alongside it, not just continue on from one of its ends.
+fn is_parallel(ls1: &LineString, ls2: &LineString, max_distance_meters: f64) -> bool {
+    let (short, long) = if ls1.length::<Euclidean>() < ls2.length::<Euclidean>() {
+        (ls1, ls2)
+    } else {
+        (ls2, ls1)
+    };
+
+    let diff = (overall_angle_degrees(short) - overall_angle_degrees(long)).abs() % 180.0;
+    if diff.min(180.0 - diff) > MAX_ANGLE_DIFFERENCE_DEGREES {
+        return false;
+    }
+
+    // Check the start, middle, and end of the shorter line are all near the longer one, and that
+    // at least the middle lands somewhere along the longer one's interior
+    for fraction in [0.0, 0.5, 1.0] {
+        let Some(pt) = short.line_interpolate_point(fraction) else {
+            return false;
+        };
+        if Euclidean::distance(&pt, long) > max_distance_meters {
+            return false;
+        }
+        if fraction == 0.5 {
+            match long.line_locate_point(&pt) {
+                Some(located) if located > 0.0 && located < 1.0 => {}
+                _ => return false,
+            }
+        }
+    }
+    true
+}
+
+fn overall_angle_degrees(linestring: &LineString) -> f64 {
+    let start = Point::from(linestring.0[0]);
+    let end = Point::from(*linestring.0.last().unwrap());
+    (end.y() - start.y())
+        .atan2(end.x() - start.x())
+        .to_degrees()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parallel_to_road(pts: [(f64, f64); 2]) -> bool {
+        let road = LineString::from(vec![(0.0, 0.0), (100.0, 0.0)]);
+        is_parallel(&road, &LineString::from(pts.to_vec()), 20.0)
+    }
+
+    #[test]
+    fn test_is_parallel() {
+        // A sidewalk alongside, in either direction
+        assert!(parallel_to_road([(10.0, 5.0), (90.0, 5.0)]));
+        assert!(parallel_to_road([(90.0, -5.0), (10.0, -5.0)]));
+
+        // Too far away
+        assert!(!parallel_to_road([(10.0, 30.0), (90.0, 30.0)]));
+        // Crossing
+        assert!(!parallel_to_road([(50.0, -10.0), (50.0, 10.0)]));
+        // Continuing on from the end
+        assert!(!parallel_to_road([(100.0, 0.0), (110.0, 0.0)]));
+    }
+}
@@ -0,0 +1,94 @@
+use geo::{Area, CoordsIter, EuclideanLength, Polygon, Simplify};
+
+use crate::validity::self_intersects;
+
+/// Removes slivers and simplifies polygons, all in the units of the working CRS
+pub struct Cleanup {
+    /// Remove polygons with less area than this
+    pub min_area: f64,
+    /// Remove polygons narrower than this, estimating the width from area and perimeter
+    pub min_width: f64,
+    /// Simplify with this tolerance. Simplification only keeps original vertices, and every removed
+    /// vertex is within this distance of the new edges, so no edge moves toward the road by more
+    /// than this.
+    pub simplify_tolerance: f64,
+    /// Totals over every call to `apply`, for the summary
+    pub stats: CleanupStats,
+}
+
+#[derive(Default)]
+pub struct CleanupStats {
+    num_polygons: usize,
+    num_slivers: usize,
+    num_invalid_simplifications: usize,
+    num_vertices_before: usize,
+    num_vertices_after: usize,
+}
+
+impl Cleanup {
+    pub fn is_enabled(&self) -> bool {
+        self.min_area > 0.0 || self.min_width > 0.0 || self.simplify_tolerance > 0.0
+    }
+
+    /// Can be called repeatedly on batches of polygons
+    pub fn apply(&mut self, polygons: Vec<Polygon>) -> Vec<Polygon> {
+        self.stats.num_polygons += polygons.len();
+        self.stats.num_vertices_before += polygons.iter().map(|p| p.coords_count()).sum::<usize>();
+
+        let mut output = Vec::new();
+        for p in polygons {
+            if self.is_sliver(&p) {
+                self.stats.num_slivers += 1;
+                continue;
+            }
+
+            if self.simplify_tolerance > 0.0 {
+                let simplified = p.simplify(&self.simplify_tolerance);
+                // Simplifying can collapse narrow parts and make rings self-intersect. Keep the
+                // original when that happens.
+                if simplified.unsigned_area() > 0.0 && !self_intersects(&simplified) {
+                    output.push(simplified);
+                } else {
+                    self.stats.num_invalid_simplifications += 1;
+                    output.push(p);
+                }
+            } else {
+                output.push(p);
+            }
+        }
+
+        self.stats.num_vertices_after += output.iter().map(|p| p.coords_count()).sum::<usize>();
+        output
+    }
+
+    pub fn print_summary(&self) {
+        let stats = &self.stats;
+        println!(
+            "Removed {} slivers out of {} polygons. Kept {} polygons unsimplified.",
+            stats.num_slivers, stats.num_polygons, stats.num_invalid_simplifications
+        );
+        println!(
+            "Reduced vertices from {} to {} ({:.1}% fewer)",
+            stats.num_vertices_before,
+            stats.num_vertices_after,
+            100.0
+                * (1.0 - stats.num_vertices_after as f64 / stats.num_vertices_before.max(1) as f64)
+        );
+    }
+
+    fn is_sliver(&self, p: &Polygon) -> bool {
+        let area = p.unsigned_area();
+        if area < self.min_area {
+            return true;
+        }
+        if self.min_width > 0.0 {
+            // For a long thin rectangle, the perimeter is about twice the length, so this is about
+            // the width
+            let perimeter = p.exterior().euclidean_length();
+            if perimeter > 0.0 && 2.0 * area / perimeter < self.min_width {
+                return true;
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{bail, Result};
+use geo::{BoundingRect, Coord, MapCoordsInPlace, Polygon};
+use proj4rs::Proj;
+
+/// A coordinate reference system, described by an EPSG code or a proj string
+pub struct Crs {
+    name: String,
+    proj: Proj,
+}
+
+impl Crs {
+    /// Accepts `EPSG:1234`, `WGS84`, or a proj string like `+proj=tmerc ...`
+    pub fn parse(name: &str) -> Result<Self> {
+        let proj = match Proj::from_user_string(name) {
+            Ok(proj) => proj,
+            Err(err) => bail!("Unknown CRS {name}: {err}"),
+        };
+        Ok(Self {
+            name: name.to_string(),
+            proj,
+        })
+    }
+
+    pub fn wgs84() -> Self {
+        Self::parse("EPSG:4326").unwrap()
+    }
+
+    /// A transverse Mercator projection centered on some WGS84 polygons, accurate in meters
+    /// nearby
+    pub fn local_transverse_mercator(polygons_wgs84: &[Polygon]) -> Result<Self> {
+        let Some(center) = bounding_rect(polygons_wgs84) else {
+            bail!("No input polygons");
+        };
+        let center = center.center();
+        Self::parse(&format!(
+            "+proj=tmerc +lat_0={} +lon_0={} +k=1 +x_0=0 +y_0=0 +ellps=WGS84 +units=m +no_defs",
+            center.y, center.x
+        ))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_wgs84(&self) -> bool {
+        self.epsg_code() == Some(4326) || self.name.eq_ignore_ascii_case("WGS84")
+    }
+
+    pub fn epsg_code(&self) -> Option<u16> {
+        let (prefix, code) = self.name.split_once(':')?;
+        if !prefix.eq_ignore_ascii_case("EPSG") {
+            return None;
+        }
+        code.parse().ok()
+    }
+
+    /// Transforms polygons in-place from this CRS to another
+    pub fn transform(&self, to: &Crs, polygons: &mut [Polygon]) -> Result<()> {
+        if self.name == to.name {
+            return Ok(());
+        }
+        for p in polygons {
+            // proj4rs uses radians for geographic coordinates
+            if self.proj.is_latlong() {
+                p.map_coords_in_place(|c| Coord {
+                    x: c.x.to_radians(),
+                    y: c.y.to_radians(),
+                });
+            }
+            if let Err(err) = proj4rs::transform::transform(&self.proj, &to.proj, p) {
+                bail!(
+                    "Couldn't transform from {} to {}: {err}",
+                    self.name,
+                    to.name
+                );
+            }
+            if to.proj.is_latlong() {
+                p.map_coords_in_place(|c| Coord {
+                    x: c.x.to_degrees(),
+                    y: c.y.to_degrees(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn bounding_rect(polygons: &[Polygon]) -> Option<geo::Rect> {
+    let mut result: Option<geo::Rect> = None;
+    for p in polygons {
+        let Some(rect) = p.bounding_rect() else {
+            continue;
+        };
+        result = Some(match result {
+            Some(r) => geo::Rect::new(
+                Coord {
+                    x: r.min().x.min(rect.min().x),
+                    y: r.min().y.min(rect.min().y),
+                },
+                Coord {
+                    x: r.max().x.max(rect.max().x),
+                    y: r.max().y.max(rect.max().y),
+                },
+            ),
+            None => rect,
+        });
+    }
+    result
+}
@@ -0,0 +1,248 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+
+use anyhow::{bail, Result};
+use flatgeobuf::{
+    FallibleStreamingIterator, FgbCrs, FgbReader, FgbWriter, FgbWriterOptions, GeometryType,
+    GeozeroGeometry,
+};
+use geo::{BoundingRect, Polygon, Rect};
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::crs::Crs;
+use crate::PROGRESS_STYLE;
+
+/// Polygons read from a file
+pub struct Input {
+    pub polygons: Vec<Polygon>,
+    /// For each polygon, the index of the feature it came from. MultiPolygons are split, so
+    /// several polygons can come from one feature.
+    pub feature_idx: Vec<usize>,
+    /// The CRS recorded in the file, if any
+    pub crs: Option<String>,
+}
+
+/// Reads polygons from GeoJSON or FlatGeobuf, splitting up any MultiPolygons
+pub fn read_polygons(path: &str) -> Result<Input> {
+    println!("Reading {path}");
+    if path.ends_with(".fgb") {
+        read_fgb(path)
+    } else {
+        read_geojson(path)
+    }
+}
+
+fn read_geojson(path: &str) -> Result<Input> {
+    let gj_string = std::fs::read_to_string(path)?;
+    let gj: geojson::GeoJson = gj_string.parse()?;
+    let mut input = Input {
+        polygons: Vec::new(),
+        feature_idx: Vec::new(),
+        crs: None,
+    };
+    let geometries: Vec<Option<geojson::Geometry>> = match gj {
+        geojson::GeoJson::FeatureCollection(fc) => {
+            // Like "urn:ogc:def:crs:EPSG::27700"
+            if let Some(name) = fc
+                .foreign_members
+                .as_ref()
+                .and_then(|x| x.get("crs"))
+                .and_then(|x| x.pointer("/properties/name"))
+                .and_then(|x| x.as_str())
+            {
+                if let Some(code) = name.split("EPSG::").nth(1) {
+                    input.crs = Some(format!("EPSG:{code}"));
+                }
+            }
+            fc.features.into_iter().map(|f| f.geometry).collect()
+        }
+        geojson::GeoJson::Feature(f) => vec![f.geometry],
+        geojson::GeoJson::Geometry(geom) => vec![Some(geom)],
+    };
+
+    for (idx, geom) in geometries.into_iter().enumerate() {
+        if let Some(geom) = geom {
+            for p in split_polygons(geom.try_into()?) {
+                input.polygons.push(p);
+                input.feature_idx.push(idx);
+            }
+        }
+    }
+    Ok(input)
+}
+
+fn read_fgb(path: &str) -> Result<Input> {
+    let mut input = Input {
+        polygons: Vec::new(),
+        feature_idx: Vec::new(),
+        crs: fgb_crs(path)?,
+    };
+    for_each_fgb_polygon(path, |idx, p| {
+        input.polygons.push(p);
+        input.feature_idx.push(idx);
+        Ok(())
+    })?;
+    Ok(input)
+}
+
+/// The CRS recorded in a FlatGeobuf file, if any
+pub fn fgb_crs(path: &str) -> Result<Option<String>> {
+    let fgb = FgbReader::open(BufReader::new(File::open(path)?))?;
+    Ok(fgb
+        .header()
+        .crs()
+        .map(|crs| crs.code())
+        .filter(|code| *code > 0)
+        .map(|code| format!("EPSG:{code}")))
+}
+
+/// The bounds of everything in a FlatGeobuf file, from its header if it's recorded there
+pub fn fgb_bounds(path: &str) -> Result<Option<Rect>> {
+    let fgb = FgbReader::open(BufReader::new(File::open(path)?))?;
+    if let Some(envelope) = fgb.header().envelope() {
+        if envelope.len() == 4 {
+            return Ok(Some(Rect::new(
+                (envelope.get(0), envelope.get(1)),
+                (envelope.get(2), envelope.get(3)),
+            )));
+        }
+    }
+
+    // Otherwise read everything
+    let mut bounds: Option<Rect> = None;
+    for_each_fgb_polygon(path, |_, p| {
+        if let Some(rect) = p.bounding_rect() {
+            bounds = Some(match bounds {
+                Some(b) => Rect::new(
+                    (b.min().x.min(rect.min().x), b.min().y.min(rect.min().y)),
+                    (b.max().x.max(rect.max().x), b.max().y.max(rect.max().y)),
+                ),
+                None => rect,
+            });
+        }
+        Ok(())
+    })?;
+    Ok(bounds)
+}
+
+/// Calls `f` with every polygon in a FlatGeobuf file and the index of the feature it came from,
+/// decoding one feature at a time instead of reading the whole file into memory
+pub fn for_each_fgb_polygon<F: FnMut(usize, Polygon) -> Result<()>>(
+    path: &str,
+    mut f: F,
+) -> Result<()> {
+    let mut features = FgbReader::open(BufReader::new(File::open(path)?))?.select_all()?;
+    let progress = ProgressBar::new(features.features_count().unwrap_or(0) as u64)
+        .with_style(ProgressStyle::with_template(PROGRESS_STYLE).unwrap());
+    let mut idx = 0;
+    while let Some(feature) = features.next()? {
+        progress.inc(1);
+        let mut geom = geozero::geo_types::GeoWriter::new();
+        feature.process_geom(&mut geom)?;
+        if let Some(geom) = geom.take_geometry() {
+            for p in split_polygons(geom) {
+                f(idx, p)?;
+            }
+        }
+        idx += 1;
+    }
+    progress.finish();
+    Ok(())
+}
+
+fn split_polygons(geom: geo::Geometry) -> Vec<Polygon> {
+    match geom {
+        geo::Geometry::Polygon(p) => vec![p],
+        geo::Geometry::MultiPolygon(mp) => mp.0,
+        _ => Vec::new(),
+    }
+}
+
+/// Writes polygons to GeoJSON or FlatGeobuf as they're produced, recording the CRS
+pub enum Writer {
+    GeoJson {
+        file: BufWriter<File>,
+        any_features: bool,
+    },
+    // FgbWriter keeps features in a temporary file until the index is built at the end
+    FlatGeobuf {
+        fgb: FgbWriter<'static>,
+        path: String,
+    },
+}
+
+impl Writer {
+    pub fn new(path: &str, crs: &Crs) -> Result<Self> {
+        println!("Writing {path}");
+        if path.ends_with(".fgb") {
+            let code = if crs.is_wgs84() {
+                4326
+            } else if let Some(code) = crs.epsg_code() {
+                code
+            } else {
+                bail!("Can't record {} in FlatGeobuf", crs.name());
+            };
+            let fgb = FgbWriter::create_with_options(
+                "dissolved",
+                GeometryType::Polygon,
+                FgbWriterOptions {
+                    // Readers use the spatial index to only fetch polygons near a route
+                    write_index: true,
+                    crs: FgbCrs {
+                        code: code.into(),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )?;
+            Ok(Self::FlatGeobuf {
+                fgb,
+                path: path.to_string(),
+            })
+        } else {
+            let mut file = BufWriter::new(File::create(path)?);
+            write!(file, "{{\"type\":\"FeatureCollection\",")?;
+            if !crs.is_wgs84() {
+                let name = format!("urn:ogc:def:crs:EPSG::{}", crs.epsg_code().unwrap());
+                let crs = serde_json::json!({ "type": "name", "properties": { "name": name } });
+                write!(file, "\"crs\":{crs},")?;
+            }
+            write!(file, "\"features\":[")?;
+            Ok(Self::GeoJson {
+                file,
+                any_features: false,
+            })
+        }
+    }
+
+    pub fn add(&mut self, p: Polygon) -> Result<()> {
+        match self {
+            Self::GeoJson { file, any_features } => {
+                if *any_features {
+                    write!(file, ",")?;
+                }
+                *any_features = true;
+                let f = geojson::Feature::from(geojson::Geometry::from(&p));
+                serde_json::to_writer(file, &f)?;
+            }
+            Self::FlatGeobuf { fgb, .. } => {
+                fgb.add_feature_geom(geo::Geometry::Polygon(p), |_| {})?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        match self {
+            Self::GeoJson { mut file, .. } => {
+                write!(file, "]}}")?;
+                file.flush()?;
+            }
+            Self::FlatGeobuf { fgb, path } => {
+                let mut file = BufWriter::new(File::create(path)?);
+                fgb.write(&mut file)?;
+            }
+        }
+        Ok(())
+    }
+}
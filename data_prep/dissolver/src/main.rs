@@ -1,89 +1,279 @@
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 
-use anyhow::Result;
-use geo::{BooleanOps, ChamberlainDuquetteArea, MultiPolygon, Polygon, Relate, SpadeBoolops};
+use anyhow::{bail, Result};
+use clap::{Parser, ValueEnum};
+use geo::{Area, Polygon, Relate};
 use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
 use rayon::prelude::*;
-use rstar::{primitives::GeomWithData, ParentNode, RTree, RTreeNode, RTreeObject};
+use rstar::{primitives::GeomWithData, RTree, RTreeObject};
 use union_find_rs::prelude::{DisjointSets, UnionFind};
 
+use crate::cleanup::Cleanup;
+use crate::crs::Crs;
+use crate::io::read_polygons;
+use crate::union::{cascading_union, Failures};
+
+mod cleanup;
+mod crs;
+mod io;
+#[path = "../../shared/panic.rs"]
+mod panic;
+mod tiled;
+#[path = "../../shared/union.rs"]
+mod union;
+#[path = "../../shared/validity.rs"]
+mod validity;
+
 static PROGRESS_STYLE: &str =
     "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {human_pos}/{human_len} ({per_sec}, {eta})";
 
-// TODO Would this work faster with planar coords?
+/// This takes a .geojson or .fgb file with polygons as input, then dissolves/merges adjacent polygons,
+/// optionally limited to a maximum area. All processing happens in a projected CRS measured in
+/// meters, and the output can be in a different CRS than the input.
+#[derive(Parser)]
+struct Args {
+    /// A .geojson or .fgb file with polygons. MultiPolygons are split into individual polygons.
+    input: String,
 
-/// This takes a .geojson file with polygons as input, then dissolves/merges adjacent polygons,
-/// limited to a max_unsigned_geodesic_area. The input and output are TODO CRS.
-fn main() -> Result<()> {
-    let args: Vec<_> = std::env::args().collect();
-    let polygons = read_polygons(&args[1])?;
+    /// Where to write the dissolved polygons, as .geojson or .fgb
+    #[arg(long, default_value = "out.geojson")]
+    output: String,
 
-    if true {
-        let output = cascading_union(polygons);
-        return write_polygons("out.geojson", output);
-    }
+    #[arg(long, value_enum, default_value_t = Algorithm::Cascading)]
+    algorithm: Algorithm,
 
-    let sets = find_all_adjacencies(&polygons);
+    /// With the area-limited algorithm, stop growing a polygon once it would exceed this area, in
+    /// square meters
+    #[arg(long, default_value_t = 15_000.0)]
+    max_area: f64,
 
-    // TODO Take as param
-    // TODO is the ChamberlainDuquetteArea approximation faster than the other trait? Are we
-    // confident the polygons have the correct winding order?
-    let max_unsigned_geodesic_area = 15_000.0;
+    /// With the tiled algorithm, the width and height of each tile, in meters
+    #[arg(long, default_value_t = 5_000.0)]
+    tile_size: f64,
 
-    println!("Unioning {} sets", sets.len());
-    let output: Vec<Polygon> = sets
-        .into_par_iter()
-        .progress_with_style(ProgressStyle::with_template(PROGRESS_STYLE).unwrap())
-        .flat_map(|set| {
-            union_all(
-                &polygons,
-                set.into_iter().collect(),
-                max_unsigned_geodesic_area,
-            )
-        })
-        .collect();
+    /// Where to write any polygons that couldn't be unioned
+    #[arg(long, default_value = "failures.geojson")]
+    failures: String,
 
-    println!("Result has {}", output.len());
-    write_polygons("out.geojson", output)?;
+    /// The CRS of the input, like `EPSG:27700`. Defaults to the CRS recorded in the input file,
+    /// or WGS84 if there isn't one.
+    #[arg(long)]
+    input_crs: Option<String>,
 
-    Ok(())
+    /// The CRS to write the output in. Defaults to the input CRS. Must be an EPSG code, so it can
+    /// be recorded in the output file.
+    #[arg(long)]
+    output_crs: Option<String>,
+
+    /// The projected CRS to do all processing in. `auto` picks a transverse Mercator projection
+    /// centered on the input, `bng` is British National Grid, and anything else is an EPSG code
+    /// or proj string.
+    #[arg(long, default_value = "auto")]
+    working_crs: String,
+
+    /// After dissolving, remove polygons smaller than this, in square meters
+    #[arg(long, default_value_t = 0.0)]
+    min_area: f64,
+
+    /// After dissolving, remove slivers narrower than this on average, in meters
+    #[arg(long, default_value_t = 0.0)]
+    min_width: f64,
+
+    /// After dissolving, simplify polygons. No edge moves toward the road by more than this many
+    /// centimeters.
+    #[arg(long, default_value_t = 0.0)]
+    simplify_cm: f64,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Algorithm {
+    /// Union everything overlapping or touching, with no limit on the output size
+    Cascading,
+    /// Only merge touching polygons until they reach a maximum area
+    AreaLimited,
+    /// Like cascading, but union tiles of a grid in parallel, then stitch them together. Use this
+    /// for very large inputs, which must be .fgb so tiles can be read separately.
+    Tiled,
+    /// Don't dissolve at all. Use this to only remove slivers and simplify.
+    None,
 }
 
-fn read_polygons(path: &str) -> Result<Vec<Polygon>> {
-    println!("Reading {path}");
-    let gj_string = std::fs::read_to_string(path)?;
-    let gj: geojson::GeoJson = gj_string.parse()?;
-    let mut polygons = Vec::new();
-    for geom in geojson::quick_collection(&gj)? {
-        if let geo::Geometry::Polygon(p) = geom {
-            polygons.push(p);
+fn main() -> Result<()> {
+    let args = Args::parse();
+    // The tiled algorithm streams the input, instead of reading it all up-front
+    let tiled = matches!(args.algorithm, Algorithm::Tiled);
+    if tiled && !args.input.ends_with(".fgb") {
+        bail!("--algorithm tiled needs a .fgb input");
+    }
+    let input = if tiled {
+        None
+    } else {
+        Some(read_polygons(&args.input)?)
+    };
+    let recorded_crs = match input {
+        Some(ref input) => input.crs.clone(),
+        None => io::fgb_crs(&args.input)?,
+    };
+
+    let input_crs = Crs::parse(
+        args.input_crs
+            .as_deref()
+            .or(recorded_crs.as_deref())
+            .unwrap_or("EPSG:4326"),
+    )?;
+    let output_crs = match args.output_crs {
+        Some(ref name) => Crs::parse(name)?,
+        None => Crs::parse(input_crs.name())?,
+    };
+    if !output_crs.is_wgs84() && output_crs.epsg_code().is_none() {
+        bail!(
+            "The output CRS must be an EPSG code, not {}",
+            output_crs.name()
+        );
+    }
+    let working_crs = match args.working_crs.as_str() {
+        "auto" => {
+            // Find the center in WGS84
+            let bbox = match input {
+                Some(ref input) => crs::bounding_rect(&input.polygons),
+                None => io::fgb_bounds(&args.input)?,
+            };
+            let Some(bbox) = bbox else {
+                bail!("No input polygons");
+            };
+            let mut bbox = vec![bbox.to_polygon()];
+            input_crs.transform(&Crs::wgs84(), &mut bbox)?;
+            Crs::local_transverse_mercator(&bbox)?
+        }
+        "bng" => Crs::parse("EPSG:27700")?,
+        name => Crs::parse(name)?,
+    };
+    println!(
+        "Working in {}, input is {}, output will be {}",
+        working_crs.name(),
+        input_crs.name(),
+        output_crs.name()
+    );
+
+    let failures = Failures::default();
+    let mut output = Output {
+        cleanup: Cleanup {
+            min_area: args.min_area,
+            min_width: args.min_width,
+            simplify_tolerance: args.simplify_cm / 100.0,
+            stats: Default::default(),
+        },
+        working_crs: &working_crs,
+        output_crs: &output_crs,
+        writer: io::Writer::new(&args.output, &output_crs)?,
+        num_polygons: 0,
+    };
+
+    match input {
+        Some(input) => {
+            let mut polygons = input.polygons;
+            input_crs.transform(&working_crs, &mut polygons)?;
+            let result = match args.algorithm {
+                Algorithm::Cascading => {
+                    println!("Cascading union of {} polygons", polygons.len());
+                    let progress = ProgressBar::new(polygons.len() as u64)
+                        .with_style(ProgressStyle::with_template(PROGRESS_STYLE).unwrap());
+                    let result = cascading_union(
+                        input
+                            .feature_idx
+                            .into_iter()
+                            .map(Some)
+                            .zip(polygons)
+                            .collect(),
+                        &progress,
+                        &failures,
+                    );
+                    progress.finish();
+                    result
+                }
+                Algorithm::AreaLimited => {
+                    area_limited_union(&polygons, &input.feature_idx, args.max_area, &failures)
+                }
+                Algorithm::Tiled => unreachable!("The tiled algorithm doesn't read everything"),
+                Algorithm::None => polygons,
+            };
+            output.add(result)?;
         }
+        None => tiled::tiled_union(
+            &args.input,
+            &input_crs,
+            &working_crs,
+            args.tile_size,
+            &failures,
+            &mut |polygons| output.add(polygons),
+        )?,
     }
-    Ok(polygons)
+
+    output.finish()?;
+    failures.write(&args.failures, |polygons| {
+        working_crs.transform(&Crs::wgs84(), polygons)
+    })
 }
 
-fn write_polygons(path: &str, polygons: Vec<Polygon>) -> Result<()> {
-    let gc = geo::GeometryCollection::from(polygons);
-    let mut fc = geojson::FeatureCollection::from(&gc);
-    if false {
-        fc.foreign_members = Some(
-            serde_json::json!({
-                "crs": { "type": "name", "properties": { "name": "urn:ogc:def:crs:EPSG::27700" } }
-            })
-            .as_object()
-            .unwrap()
-            .clone(),
-        );
+/// Cleans up dissolved polygons, then writes them in the output CRS
+struct Output<'a> {
+    cleanup: Cleanup,
+    working_crs: &'a Crs,
+    output_crs: &'a Crs,
+    writer: io::Writer,
+    num_polygons: usize,
+}
+
+impl Output<'_> {
+    fn add(&mut self, mut polygons: Vec<Polygon>) -> Result<()> {
+        if self.cleanup.is_enabled() {
+            polygons = self.cleanup.apply(polygons);
+        }
+        self.working_crs.transform(self.output_crs, &mut polygons)?;
+        self.num_polygons += polygons.len();
+        for p in polygons {
+            self.writer.add(p)?;
+        }
+        Ok(())
     }
-    std::fs::write(path, serde_json::to_string(&fc)?)?;
-    Ok(())
+
+    fn finish(self) -> Result<()> {
+        if self.cleanup.is_enabled() {
+            self.cleanup.print_summary();
+        }
+        println!("Result has {}", self.num_polygons);
+        self.writer.finish()
+    }
+}
+
+fn area_limited_union(
+    polygons: &[Polygon],
+    feature_idx: &[usize],
+    max_area: f64,
+    failures: &Failures,
+) -> Vec<Polygon> {
+    let sets = find_all_adjacencies(polygons);
+
+    println!("Unioning {} sets", sets.len());
+    sets.into_par_iter()
+        .progress_with_style(ProgressStyle::with_template(PROGRESS_STYLE).unwrap())
+        .flat_map(|set| {
+            union_all(
+                polygons,
+                feature_idx,
+                set.into_iter().collect(),
+                max_area,
+                failures,
+            )
+        })
+        .collect()
 }
 
 // Returns disjoint sets of indices into polygons, where each set has polygons touching each other
 // TODO Could parallelize this too, since DisjointSets could be combined
 // TODO We could limit area at this stage too, if we could keep a sum per disjoint set as we go,
 // and refuse to join.
-fn find_all_adjacencies(polygons: &Vec<Polygon>) -> Vec<HashSet<usize>> {
+fn find_all_adjacencies(polygons: &[Polygon]) -> Vec<HashSet<usize>> {
     println!("Making rtree");
     // TODO Is the clone avoidable?
     let rtree = RTree::bulk_load(
@@ -120,131 +310,63 @@ fn find_all_adjacencies(polygons: &Vec<Polygon>) -> Vec<HashSet<usize>> {
     sets.into_iter().collect()
 }
 
-// If the area isn't constrained, this should usually return exactly one polygon
+// Greedily grows groups of touching polygons from a seed, refusing to add anything that would push
+// the group over the area limit. Each group is then unioned. If the area isn't constrained, this
+// should usually return exactly one polygon.
 fn union_all(
-    polygons: &Vec<Polygon>,
-    mut indices: Vec<usize>,
-    max_unsigned_geodesic_area: f64,
+    polygons: &[Polygon],
+    feature_idx: &[usize],
+    indices: Vec<usize>,
+    max_area: f64,
+    failures: &Failures,
 ) -> Vec<Polygon> {
-    // TODO Temporarily, give up when the input is too big
-    if indices.len() > 1000 {
-        println!("Skipping huge set with {}", indices.len());
-        return indices
-            .into_iter()
-            .map(|idx| polygons[idx].clone())
-            .collect();
-    }
-
-    let mut out = Vec::new();
-
-    let mut current = MultiPolygon::new(vec![polygons[indices.pop().unwrap()].clone()]);
-    while !indices.is_empty() {
-        // If the current polygon is too big, start a new one
-        if current.chamberlain_duquette_unsigned_area() > max_unsigned_geodesic_area {
-            out.extend(current.0);
-            current = MultiPolygon::new(vec![polygons[indices.pop().unwrap()].clone()]);
-            // TODO Could we reuse the rtree here to prune the search for the huge sets?
-        } else if let Some(idx_of_idx) = indices
+    // Polygons in one set only touch, so the area of their union is just the sum
+    let areas: HashMap<usize, f64> = indices
+        .iter()
+        .map(|idx| (*idx, polygons[*idx].unsigned_area()))
+        .collect();
+    // Large sets are common, so avoid checking every pair
+    let rtree = RTree::bulk_load(
+        indices
             .iter()
-            .position(|idx| current.relate(&polygons[*idx]).is_touches())
-        {
-            // Union the current polygon with something else
-            // (Maybe a Vec of indices is a weird data structure)
-            let idx = indices.remove(idx_of_idx);
-            current = current.union(&MultiPolygon::new(vec![polygons[idx].clone()]));
-        } else {
-            // The current polygon doesn't touch anything, so start a new group
-            out.extend(current.0);
-            current = MultiPolygon::new(vec![polygons[indices.pop().unwrap()].clone()]);
-        }
-    }
-    out.extend(current.0);
-
-    out
-}
-
-fn cascading_union(polygons: Vec<Polygon>) -> Vec<Polygon> {
-    let len = polygons.len();
-    println!("Making rtree for {len} polygons");
-    let rtree = RTree::bulk_load(polygons);
-
-    println!("Cascading union");
-    let progress = ProgressBar::new(len as u64)
-        .with_style(ProgressStyle::with_template(PROGRESS_STYLE).unwrap());
-
-    // From https://gist.github.com/urschrei/cd80b4d2ec3c75f12fa541a5bdbf6489
-    let init = || MultiPolygon::<f64>::new(vec![]);
-    let fold = |accum: MultiPolygon<f64>, poly: &Polygon<f64>| -> MultiPolygon<f64> {
-        progress.inc(1);
-        // NB the argument to union here is wrong / costly, because it won't accept &Polygon
-        // Perhaps our current union method (which accepts &Self) is too strict?
-        union(&accum, &MultiPolygon::new(vec![poly.clone()]))
-    };
-    let reduce = |accum1: MultiPolygon<f64>, accum2: MultiPolygon<f64>| -> MultiPolygon<f64> {
-        union(&accum1, &accum2)
-    };
+            .map(|idx| GeomWithData::new(polygons[*idx].clone(), *idx))
+            .collect(),
+    );
+    let mut remaining: BTreeSet<usize> = indices.into_iter().collect();
 
-    let result = bottom_up_fold_reduce(&rtree, init, fold, reduce).0;
-    progress.finish();
-    result
-}
+    let mut out = Vec::new();
+    while let Some(seed) = remaining.pop_first() {
+        let mut group = vec![seed];
+        let mut area = areas[&seed];
+        let mut queue = VecDeque::from([seed]);
 
-fn union(mp1: &MultiPolygon, mp2: &MultiPolygon) -> MultiPolygon {
-    // Fast, works on WGS84 or planar, crashy
-    if true {
-        println!("union of {} + {}", mp1.0.len(), mp2.0.len());
-        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| mp1.union(mp2))) {
-            Ok(result) => {
-                println!("  done with that union, got {} in result", result.0.len());
-                result
-            }
-            Err(err) => {
-                println!("Crash {err:?}");
-                // Give up on unioning here
-                let mut polygons = mp1.0.clone();
-                polygons.extend(mp2.0.clone());
-                MultiPolygon::new(polygons)
+        while let Some(idx1) = queue.pop_front() {
+            for obj in rtree.locate_in_envelope_intersecting(&polygons[idx1].envelope()) {
+                let idx2 = obj.data;
+                if !remaining.contains(&idx2) || area + areas[&idx2] > max_area {
+                    continue;
+                }
+                if polygons[idx1].relate(obj.geom()).is_touches() {
+                    remaining.remove(&idx2);
+                    area += areas[&idx2];
+                    group.push(idx2);
+                    queue.push_back(idx2);
+                }
             }
         }
-    } else {
-        // Slow, works on planar, not crashy
-        SpadeBoolops::union(mp1, mp2).unwrap()
-    }
-}
-
-// From https://gist.github.com/urschrei/cd80b4d2ec3c75f12fa541a5bdbf6489
-// TODO Try the rayon one
-fn bottom_up_fold_reduce<T, S, I, F, R>(
-    tree: &RTree<T>,
-    mut init: I,
-    mut fold: F,
-    mut reduce: R,
-) -> S
-where
-    T: RTreeObject,
-    I: FnMut() -> S,
-    F: FnMut(S, &T) -> S,
-    R: FnMut(S, S) -> S,
-{
-    fn inner<T, S, I, F, R>(parent: &ParentNode<T>, init: &mut I, fold: &mut F, reduce: &mut R) -> S
-    where
-        T: RTreeObject,
-        I: FnMut() -> S,
-        F: FnMut(S, &T) -> S,
-        R: FnMut(S, S) -> S,
-    {
-        parent
-            .children()
-            .iter()
-            .fold(init(), |accum, child| match child {
-                RTreeNode::Leaf(value) => fold(accum, value),
-                RTreeNode::Parent(parent) => {
-                    let value = inner(&parent, init, fold, reduce);
 
-                    reduce(accum, value)
-                }
-            })
+        if group.len() == 1 {
+            out.push(polygons[seed].clone());
+        } else {
+            out.extend(cascading_union(
+                group
+                    .into_iter()
+                    .map(|idx| (Some(feature_idx[idx]), polygons[idx].clone()))
+                    .collect(),
+                &ProgressBar::hidden(),
+                failures,
+            ));
+        }
     }
-
-    inner(tree.root(), &mut init, &mut fold, &mut reduce)
+    out
 }
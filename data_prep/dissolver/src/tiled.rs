@@ -0,0 +1,309 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+use anyhow::Result;
+use flatgeobuf::{
+    ColumnType, FallibleStreamingIterator, FgbReader, FgbWriter, FgbWriterOptions, GeometryType,
+    GeozeroGeometry,
+};
+use geo::{BoundingRect, Intersects, Polygon, Rect};
+use geozero::{ColumnValue, FeatureProperties, PropertyProcessor};
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use rstar::{
+    primitives::{GeomWithData, Rectangle},
+    RTree, RTreeObject,
+};
+use union_find_rs::prelude::{DisjointSets, UnionFind};
+
+use crate::crs::Crs;
+use crate::io::for_each_fgb_polygon;
+use crate::union::{cascading_union, Failures};
+use crate::PROGRESS_STYLE;
+
+/// Splits the polygons into a grid of square tiles, with `tile_size` in the units of the working
+/// CRS, and unions each tile independently in parallel. Polygons are assigned to the tile
+/// containing their center, so nothing is split. Then polygons that touch the edge of their tile
+/// are stitched together with whatever they touch in neighbouring tiles.
+///
+/// Each union only involves polygons from one tile or one seam, so no huge multipolygons are built
+/// up like in a single cascading union over everything. The input is first copied to a temporary
+/// FlatGeobuf file in the working CRS, so each tile can be read from it separately, and finished
+/// polygons are passed to `emit` as soon as possible. Only polygons on the edges of tiles are kept
+/// in memory.
+pub fn tiled_union(
+    input_path: &str,
+    input_crs: &Crs,
+    working_crs: &Crs,
+    tile_size: f64,
+    failures: &Failures,
+    emit: &mut dyn FnMut(Vec<Polygon>) -> Result<()>,
+) -> Result<()> {
+    let tiles_path = std::env::temp_dir()
+        .join(format!("dissolver_tiles_{}.fgb", std::process::id()))
+        .to_string_lossy()
+        .to_string();
+    let tiles = split_into_tiles(input_path, input_crs, working_crs, tile_size, &tiles_path)?;
+
+    println!("Unioning {} tiles", tiles.len());
+    let progress = ProgressBar::new(tiles.len() as u64)
+        .with_style(ProgressStyle::with_template(PROGRESS_STYLE).unwrap());
+    let mut seams = BTreeMap::new();
+    // Only work on a few tiles at once, so finished polygons are written out before reading more
+    for batch in tiles.chunks(4 * rayon::current_num_threads()) {
+        let results = batch
+            .par_iter()
+            .map(|tile| {
+                let polygons = read_tile(&tiles_path, *tile, tile_size)?;
+                let unioned = cascading_union(polygons, &ProgressBar::hidden(), failures);
+                progress.inc(1);
+                Ok((
+                    *tile,
+                    split_by_edge(unioned, block_bounds(*tile, (1, 1), tile_size)),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for (tile, (on_edge, finished)) in results {
+            emit(finished)?;
+            if !on_edge.is_empty() {
+                seams.insert(tile, on_edge);
+            }
+        }
+    }
+    progress.finish();
+    std::fs::remove_file(&tiles_path)?;
+
+    stitch(seams, tile_size, failures, emit)
+}
+
+// Writes every input polygon, in the working CRS, to a FlatGeobuf file with a spatial index and
+// the polygon's input feature. Returns the tiles containing the center of any polygon.
+fn split_into_tiles(
+    input_path: &str,
+    input_crs: &Crs,
+    working_crs: &Crs,
+    tile_size: f64,
+    tiles_path: &str,
+) -> Result<Vec<(i64, i64)>> {
+    println!("Splitting {input_path} into tiles");
+    let mut fgb = FgbWriter::create_with_options(
+        "tiles",
+        GeometryType::Polygon,
+        FgbWriterOptions {
+            write_index: true,
+            ..Default::default()
+        },
+    )?;
+    fgb.add_column("feature_idx", ColumnType::ULong, |_, _| {});
+
+    let mut tiles = BTreeSet::new();
+    for_each_fgb_polygon(input_path, |idx, p| {
+        let mut polygons = [p];
+        input_crs.transform(working_crs, &mut polygons)?;
+        let [p] = polygons;
+        let Some(bbox) = p.bounding_rect() else {
+            return Ok(());
+        };
+        tiles.insert(tile_for(bbox, tile_size));
+
+        let mut result = Ok(());
+        fgb.add_feature_geom(geo::Geometry::Polygon(p), |feat| {
+            result = feat
+                .property(0, "feature_idx", &ColumnValue::ULong(idx as u64))
+                .map(|_| ());
+        })?;
+        result?;
+        Ok(())
+    })?;
+
+    fgb.write(&mut BufWriter::new(File::create(tiles_path)?))?;
+    Ok(tiles.into_iter().collect())
+}
+
+// Reads the polygons with their center in one tile, with their input feature
+fn read_tile(
+    tiles_path: &str,
+    tile: (i64, i64),
+    tile_size: f64,
+) -> Result<Vec<(Option<usize>, Polygon)>> {
+    let bounds = block_bounds(tile, (1, 1), tile_size);
+    let mut features = FgbReader::open(BufReader::new(File::open(tiles_path)?))?.select_bbox(
+        bounds.min().x,
+        bounds.min().y,
+        bounds.max().x,
+        bounds.max().y,
+    )?;
+
+    let mut polygons = Vec::new();
+    while let Some(feature) = features.next()? {
+        let mut geom = geozero::geo_types::GeoWriter::new();
+        feature.process_geom(&mut geom)?;
+        let Some(geo::Geometry::Polygon(p)) = geom.take_geometry() else {
+            continue;
+        };
+        // Polygons overlapping several tiles are returned for each of them
+        if p.bounding_rect().map(|bbox| tile_for(bbox, tile_size)) != Some(tile) {
+            continue;
+        }
+        let mut feature_idx = FeatureIdx(None);
+        feature.process_properties(&mut feature_idx)?;
+        polygons.push((feature_idx.0, p));
+    }
+    Ok(polygons)
+}
+
+struct FeatureIdx(Option<usize>);
+
+impl PropertyProcessor for FeatureIdx {
+    fn property(
+        &mut self,
+        _: usize,
+        name: &str,
+        value: &ColumnValue,
+    ) -> geozero::error::Result<bool> {
+        if let ("feature_idx", ColumnValue::ULong(x)) = (name, value) {
+            self.0 = Some(*x as usize);
+        }
+        Ok(false)
+    }
+}
+
+fn tile_for(bbox: Rect, tile_size: f64) -> (i64, i64) {
+    let center = bbox.center();
+    (
+        (center.x / tile_size).floor() as i64,
+        (center.y / tile_size).floor() as i64,
+    )
+}
+
+// A block of `size` tiles, numbered like tiles at that scale
+fn block_bounds(block: (i64, i64), size: (i64, i64), tile_size: f64) -> Rect {
+    let width = size.0 as f64 * tile_size;
+    let height = size.1 as f64 * tile_size;
+    Rect::new(
+        (block.0 as f64 * width, block.1 as f64 * height),
+        ((block.0 + 1) as f64 * width, (block.1 + 1) as f64 * height),
+    )
+}
+
+// Returns the polygons touching the edge of the bounds, and everything else
+fn split_by_edge(polygons: Vec<Polygon>, bounds: Rect) -> (Vec<Polygon>, Vec<Polygon>) {
+    polygons.into_iter().partition(|p| {
+        let Some(bbox) = p.bounding_rect() else {
+            return false;
+        };
+        bbox.min().x <= bounds.min().x
+            || bbox.min().y <= bounds.min().y
+            || bbox.max().x >= bounds.max().x
+            || bbox.max().y >= bounds.max().y
+    })
+}
+
+// Starting with the polygons on the edge of each tile, repeatedly merges pairs of neighbouring
+// blocks of tiles, alternating between horizontal and vertical neighbours, until one block covers
+// everything. Each pair is independent, so this happens in parallel. After each round, anything
+// no longer on the edge of its block can't touch anything else, so it's finished.
+fn stitch(
+    mut blocks: BTreeMap<(i64, i64), Vec<Polygon>>,
+    tile_size: f64,
+    failures: &Failures,
+    emit: &mut dyn FnMut(Vec<Polygon>) -> Result<()>,
+) -> Result<()> {
+    // In tiles
+    let mut size = (1, 1);
+    while blocks.len() > 1 {
+        let horizontal = size.0 <= size.1;
+        let mut pairs: BTreeMap<(i64, i64), [Vec<Polygon>; 2]> = BTreeMap::new();
+        for ((x, y), polygons) in blocks {
+            let (pair, side) = if horizontal {
+                ((x.div_euclid(2), y), x.rem_euclid(2))
+            } else {
+                ((x, y.div_euclid(2)), y.rem_euclid(2))
+            };
+            pairs.entry(pair).or_default()[side as usize] = polygons;
+        }
+        if horizontal {
+            size.0 *= 2;
+        } else {
+            size.1 *= 2;
+        }
+
+        println!(
+            "Stitching {} blocks of {}x{} tiles",
+            pairs.len(),
+            size.0,
+            size.1
+        );
+        let results: Vec<_> = pairs
+            .into_par_iter()
+            .map(|(block, [side1, side2])| {
+                let merged = merge_neighbours(side1, side2, failures);
+                (
+                    block,
+                    split_by_edge(merged, block_bounds(block, size, tile_size)),
+                )
+            })
+            .collect();
+
+        blocks = BTreeMap::new();
+        for (block, (on_edge, finished)) in results {
+            emit(finished)?;
+            if !on_edge.is_empty() {
+                blocks.insert(block, on_edge);
+            }
+        }
+    }
+
+    // Nothing is left to stitch with the last block
+    for (_, polygons) in blocks {
+        emit(polygons)?;
+    }
+    Ok(())
+}
+
+// Unions polygons from two neighbouring blocks with anything they touch in the other one.
+// Polygons within one block were already unioned, so they don't touch each other.
+fn merge_neighbours(side1: Vec<Polygon>, side2: Vec<Polygon>, failures: &Failures) -> Vec<Polygon> {
+    if side1.is_empty() || side2.is_empty() {
+        return side1.into_iter().chain(side2).collect();
+    }
+    let num_side1 = side1.len();
+    let polygons: Vec<Polygon> = side1.into_iter().chain(side2).collect();
+
+    // Only the bounding boxes go in the RTree, to avoid copying the polygons
+    let rtree = RTree::bulk_load(
+        polygons
+            .iter()
+            .enumerate()
+            .skip(num_side1)
+            .map(|(idx, p)| GeomWithData::new(Rectangle::from_aabb(p.envelope()), idx))
+            .collect(),
+    );
+    let mut sets = DisjointSets::new();
+    for idx in 0..polygons.len() {
+        sets.make_set(idx).unwrap();
+    }
+    for (idx1, p1) in polygons.iter().enumerate().take(num_side1) {
+        for obj in rtree.locate_in_envelope_intersecting(&p1.envelope()) {
+            if p1.intersects(&polygons[obj.data]) {
+                sets.union(&idx1, &obj.data).unwrap();
+            }
+        }
+    }
+
+    let mut polygons: Vec<Option<Polygon>> = polygons.into_iter().map(Some).collect();
+    let mut result = Vec::new();
+    for set in sets {
+        let group: Vec<(Option<usize>, Polygon)> = set
+            .into_iter()
+            .map(|idx| (None, polygons[idx].take().unwrap()))
+            .collect();
+        if group.len() == 1 {
+            result.extend(group.into_iter().map(|(_, p)| p));
+        } else {
+            result.extend(cascading_union(group, &ProgressBar::hidden(), failures));
+        }
+    }
+    result
+}
@@ -0,0 +1,240 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+use anyhow::{bail, Result};
+use flatgeobuf::{
+    ColumnType, FallibleStreamingIterator, FgbReader, FgbWriter, GeometryType, GeozeroGeometry,
+};
+use geo::{Geometry, MultiPolygon};
+use geozero::{geo_types::GeoWriter, ColumnValue, FeatureProperties, PropertyProcessor};
+use progress_log::ProgressLog;
+use serde::{Deserialize, Serialize};
+
+use crate::reassemble::Fragment;
+
+/// Records converted fragments and finished tiles, so a long run can resume after a crash. The
+/// fragments from each batch of tiles go in their own FlatGeobuf file next to the checkpoint. The
+/// checkpoint file has one JSON line per batch after the header.
+pub struct Checkpoint {
+    path: String,
+    log: ProgressLog,
+    done: HashSet<(u32, u32)>,
+    attributes: Vec<String>,
+    num_batches: usize,
+}
+
+impl Checkpoint {
+    /// Doesn't record anything
+    pub fn none() -> Self {
+        Self {
+            path: String::new(),
+            log: ProgressLog::none(),
+            done: HashSet::new(),
+            attributes: Vec::new(),
+            num_batches: 0,
+        }
+    }
+
+    /// Starts a new checkpoint file, or if `resume` is true, continues an existing one with the
+    /// same `header` and returns all fragments from finished tiles
+    pub fn open(
+        path: &str,
+        header: &str,
+        attributes: &[String],
+        resume: bool,
+    ) -> Result<(Self, Vec<Fragment>)> {
+        let mut checkpoint = Self {
+            path: path.to_string(),
+            log: ProgressLog::none(),
+            done: HashSet::new(),
+            attributes: attributes.to_vec(),
+            num_batches: 0,
+        };
+        if !resume {
+            checkpoint.log = ProgressLog::create(path, header)?;
+            return Ok((checkpoint, Vec::new()));
+        }
+
+        let (log, records) = ProgressLog::resume(path, header)?;
+        checkpoint.log = log;
+        let mut fragments = Vec::new();
+        for record in records {
+            let Ok(batch) = serde_json::from_str::<Batch>(&record) else {
+                bail!("Bad line in {path}: {record}");
+            };
+            if let Some(chunk) = batch.chunk {
+                fragments.extend(read_chunk(&chunk, attributes.len())?);
+            }
+            checkpoint.done.extend(batch.tiles);
+            checkpoint.num_batches += 1;
+        }
+        println!(
+            "Resuming from {path}, {} tiles and {} fragments already done",
+            checkpoint.done.len(),
+            fragments.len()
+        );
+        Ok((checkpoint, fragments))
+    }
+
+    pub fn is_done(&self, tile: (u32, u32)) -> bool {
+        self.done.contains(&tile)
+    }
+
+    /// Records all the fragments from some tiles, then marks the tiles as finished
+    pub fn record(&mut self, tiles: &[(u32, u32)], fragments: &[Fragment]) -> Result<()> {
+        if self.path.is_empty() {
+            return Ok(());
+        }
+        // A batch file left by a crash before its line was written gets overwritten here
+        let chunk = if fragments.is_empty() {
+            None
+        } else {
+            let chunk = format!("{}.{}.fgb", self.path, self.num_batches);
+            write_chunk(&chunk, &self.attributes, fragments)?;
+            Some(chunk)
+        };
+        self.num_batches += 1;
+
+        // Only mark the tiles finished once their fragments are safely written
+        let batch = Batch {
+            chunk,
+            tiles: tiles.to_vec(),
+        };
+        self.log.append(&serde_json::to_string(&batch)?)?;
+        self.done.extend(tiles.iter().cloned());
+        Ok(())
+    }
+}
+
+/// One line of the checkpoint file
+#[derive(Serialize, Deserialize)]
+struct Batch {
+    /// The FlatGeobuf file with the batch's fragments, if there were any
+    chunk: Option<String>,
+    tiles: Vec<(u32, u32)>,
+}
+
+// Columns before the attributes, in order
+const ID: usize = 0;
+const TILE_X: usize = 1;
+const TILE_Y: usize = 2;
+const ON_SEAM: usize = 3;
+const LAYER: usize = 4;
+const NUM_FIXED_COLUMNS: usize = 5;
+
+fn write_chunk(path: &str, attributes: &[String], fragments: &[Fragment]) -> Result<()> {
+    let mut fgb = FgbWriter::create("fragments", GeometryType::MultiPolygon)?;
+    fgb.add_column("id", ColumnType::ULong, |_, col| {
+        col.nullable = true;
+    });
+    fgb.add_column("tile_x", ColumnType::UInt, |_, _| {});
+    fgb.add_column("tile_y", ColumnType::UInt, |_, _| {});
+    fgb.add_column("on_seam", ColumnType::Bool, |_, _| {});
+    fgb.add_column("layer", ColumnType::String, |_, _| {});
+    // Attribute names could clash with the columns above
+    let names: Vec<String> = attributes
+        .iter()
+        .map(|name| format!("attribute_{name}"))
+        .collect();
+    for name in &names {
+        fgb.add_column(name, ColumnType::String, |_, col| {
+            col.nullable = true;
+        });
+    }
+
+    for f in fragments {
+        let mut result = Ok(());
+        fgb.add_feature_geom(Geometry::MultiPolygon(f.geometry.clone()), |feat| {
+            let mut values = vec![
+                (ID, "id", f.id.map(ColumnValue::ULong)),
+                (TILE_X, "tile_x", Some(ColumnValue::UInt(f.tile.0))),
+                (TILE_Y, "tile_y", Some(ColumnValue::UInt(f.tile.1))),
+                (ON_SEAM, "on_seam", Some(ColumnValue::Bool(f.on_seam))),
+                (LAYER, "layer", Some(ColumnValue::String(&f.layer))),
+            ];
+            for (idx, (name, value)) in names.iter().zip(&f.properties).enumerate() {
+                values.push((
+                    NUM_FIXED_COLUMNS + idx,
+                    name.as_str(),
+                    value.as_deref().map(ColumnValue::String),
+                ));
+            }
+            for (idx, name, value) in values {
+                if let Some(value) = value {
+                    if let Err(err) = feat.property(idx, name, &value) {
+                        result = Err(err);
+                    }
+                }
+            }
+        })?;
+        result?;
+    }
+
+    let mut file = BufWriter::new(File::create(path)?);
+    fgb.write(&mut file)?;
+    file.into_inner()?.sync_data()?;
+    Ok(())
+}
+
+fn read_chunk(path: &str, num_attributes: usize) -> Result<Vec<Fragment>> {
+    let fgb = FgbReader::open(BufReader::new(File::open(path)?))?;
+    // Chunks from older versions don't record the layer, so their columns are in other places
+    let has_layer = fgb.header().columns().is_some_and(|columns| {
+        columns
+            .iter()
+            .nth(LAYER)
+            .is_some_and(|col| col.name() == "layer")
+    });
+    if !has_layer {
+        bail!("{path} is from an older version of fix_osmm, so start again without --resume");
+    }
+    let mut fgb = fgb.select_all()?;
+    let mut fragments = Vec::new();
+    while let Some(feature) = fgb.next()? {
+        let mut geometry = GeoWriter::new();
+        feature.process_geom(&mut geometry)?;
+        let geometry = match geometry.take_geometry() {
+            Some(Geometry::MultiPolygon(mp)) => mp,
+            Some(Geometry::Polygon(p)) => MultiPolygon::from(p),
+            _ => bail!("Fragment in {path} isn't a MultiPolygon"),
+        };
+        let mut properties = FragmentProperties(Fragment {
+            layer: String::new(),
+            id: None,
+            properties: vec![None; num_attributes],
+            geometry,
+            tile: (0, 0),
+            on_seam: false,
+        });
+        feature.process_properties(&mut properties)?;
+        fragments.push(properties.0);
+    }
+    Ok(fragments)
+}
+
+struct FragmentProperties(Fragment);
+
+impl PropertyProcessor for FragmentProperties {
+    fn property(
+        &mut self,
+        idx: usize,
+        _: &str,
+        value: &ColumnValue,
+    ) -> geozero::error::Result<bool> {
+        match (idx, value) {
+            (ID, ColumnValue::ULong(x)) => self.0.id = Some(*x),
+            (TILE_X, ColumnValue::UInt(x)) => self.0.tile.0 = *x,
+            (TILE_Y, ColumnValue::UInt(x)) => self.0.tile.1 = *x,
+            (ON_SEAM, ColumnValue::Bool(x)) => self.0.on_seam = *x,
+            (LAYER, ColumnValue::String(x)) => self.0.layer = x.to_string(),
+            (idx, ColumnValue::String(x)) if idx >= NUM_FIXED_COLUMNS => {
+                if let Some(slot) = self.0.properties.get_mut(idx - NUM_FIXED_COLUMNS) {
+                    *slot = Some(x.to_string());
+                }
+            }
+            _ => {}
+        }
+        Ok(false)
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Result};
+use geozero::mvt::tile::{Feature, Layer, Value};
+
+/// Decides which layers and features to convert
+pub struct Filter {
+    /// Empty means every layer
+    layers: HashSet<String>,
+    /// If non-empty, only keep features with one of these key/value pairs
+    include: HashMap<String, HashSet<String>>,
+    /// Skip features with any of these key/value pairs
+    exclude: HashMap<String, HashSet<String>>,
+}
+
+impl Filter {
+    /// `include` and `exclude` have entries like `style_description=Road Or Track Fill`
+    pub fn new(layers: Vec<String>, include: &[String], exclude: &[String]) -> Result<Self> {
+        Ok(Self {
+            layers: layers.into_iter().collect(),
+            include: parse_pairs(include)?,
+            exclude: parse_pairs(exclude)?,
+        })
+    }
+
+    pub fn keep_layer(&self, layer: &Layer) -> bool {
+        self.layers.is_empty() || self.layers.contains(&layer.name)
+    }
+
+    /// `properties` should come from [`properties`]
+    pub fn keep_feature(&self, properties: &[(&str, String)]) -> bool {
+        let matches = |pairs: &HashMap<String, HashSet<String>>| {
+            properties
+                .iter()
+                .any(|(key, value)| pairs.get(*key).is_some_and(|values| values.contains(value)))
+        };
+        if !self.include.is_empty() && !matches(&self.include) {
+            return false;
+        }
+        !matches(&self.exclude)
+    }
+}
+
+fn parse_pairs(pairs: &[String]) -> Result<HashMap<String, HashSet<String>>> {
+    let mut result: HashMap<String, HashSet<String>> = HashMap::new();
+    for pair in pairs {
+        let Some((key, value)) = pair.split_once('=') else {
+            bail!("Filters must look like key=value, not {pair}");
+        };
+        result
+            .entry(key.to_string())
+            .or_default()
+            .insert(value.to_string());
+    }
+    Ok(result)
+}
+
+/// Decodes the attributes of one feature, with every value as a string
+pub fn properties<'a>(layer: &'a Layer, feature: &Feature) -> Vec<(&'a str, String)> {
+    let mut result = Vec::new();
+    // Tags are pairs of indices into the layer's keys and values
+    for pair in feature.tags.chunks_exact(2) {
+        let (Some(key), Some(value)) = (
+            layer.keys.get(pair[0] as usize),
+            layer.values.get(pair[1] as usize),
+        ) else {
+            continue;
+        };
+        if let Some(value) = value_to_string(value) {
+            result.push((key.as_str(), value));
+        }
+    }
+    result
+}
+
+fn value_to_string(value: &Value) -> Option<String> {
+    if let Some(ref x) = value.string_value {
+        return Some(x.clone());
+    }
+    if let Some(x) = value.float_value {
+        return Some(x.to_string());
+    }
+    if let Some(x) = value.double_value {
+        return Some(x.to_string());
+    }
+    if let Some(x) = value.int_value.or(value.sint_value) {
+        return Some(x.to_string());
+    }
+    if let Some(x) = value.uint_value {
+        return Some(x.to_string());
+    }
+    value.bool_value.map(|x| x.to_string())
+}
@@ -1,79 +1,335 @@
+mod checkpoint;
+mod filter;
+#[path = "../../shared/panic.rs"]
+mod panic;
+mod reassemble;
+mod tiles;
 mod wrap;
 
 use std::f64::consts::PI;
 use std::fs::File;
 use std::io::{BufWriter, Cursor, Read};
 
-use anyhow::Result;
-use flatgeobuf::{FgbWriter, GeometryType};
-use geozero::{mvt::Message, GeozeroDatasource};
+use anyhow::{bail, Result};
+use clap::Parser;
+use flatgeobuf::{ColumnType, FgbWriter, GeometryType};
+use geo::{Geometry, MultiPolygon, Rect};
+use geozero::{
+    geo_types::GeoWriter, mvt::Message, ColumnValue, GeozeroGeometry, PropertyProcessor,
+};
 use indicatif::{ProgressBar, ProgressStyle};
 use mbtiles::Mbtiles;
+use rayon::prelude::*;
+
+use crate::checkpoint::Checkpoint;
+use crate::filter::Filter;
+use crate::reassemble::{Fragment, Reassembler};
+
+/// How many tiles to decode in parallel at a time
+const BATCH_SIZE: usize = 1000;
+
+/// The classes of space that vehicles can use, for `--positive-space`
+const POSITIVE_SPACE: [&str; 2] = [
+    "style_description=Road Or Track Fill",
+    "style_description=Roadside Manmade Fill",
+];
 
 /// This script takes the `OSMasterMapTopography_gb_TopographicArea.mbtiles` file as input and
-/// converts it to flatgeobuf. It's specialized for this one file.
+/// converts polygons from it to flatgeobuf.
+#[derive(Parser)]
+struct Args {
+    /// An .mbtiles file with gzipped vector tiles
+    input: String,
+
+    #[arg(long, default_value = "out.fgb")]
+    output: String,
+
+    /// The zoom level to read. Defaults to the zoom from the mbtiles metadata, if minzoom and
+    /// maxzoom are the same.
+    #[arg(long)]
+    zoom: Option<u8>,
+
+    /// Only convert these layers, comma-separated. By default, every layer is converted.
+    #[arg(long, value_delimiter = ',')]
+    layers: Vec<String>,
+
+    /// Skip features with an attribute, like `style_description=Road Or Track Fill`. Repeat to
+    /// skip more. By default, road and path fills are skipped.
+    #[arg(
+        long,
+        default_values = [
+            "style_description=Road Or Track Fill",
+            "style_description=Roadside Manmade Fill",
+            "style_description=Path Fill",
+            "style_description=Traffic Calming Fill",
+        ]
+    )]
+    exclude: Vec<String>,
+
+    /// Only keep features with an attribute, like `style_description=Road Or Track Fill`. Repeat
+    /// to keep more. Exclusions still apply.
+    #[arg(long)]
+    include: Vec<String>,
+
+    /// Keep only road space (road and roadside fills) instead of obstacles, for the positive-space
+    /// mode in `widths`. Replaces `--include` and `--exclude`.
+    #[arg(long)]
+    positive_space: bool,
+
+    /// Feature attributes to keep in the output, comma-separated
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "descriptive_group,style_description"
+    )]
+    attributes: Vec<String>,
+
+    /// Only convert tiles overlapping this WGS84 bounding box, given as
+    /// `min_lon,min_lat,max_lon,max_lat`
+    #[arg(long, value_delimiter = ',', allow_hyphen_values = true)]
+    bbox: Option<Vec<f64>>,
+
+    /// Record converted tiles in this file, so an interrupted run can be resumed
+    #[arg(long)]
+    checkpoint: Option<String>,
+
+    /// Skip tiles already recorded in the checkpoint file
+    #[arg(long, requires = "checkpoint")]
+    resume: bool,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args: Vec<_> = std::env::args().collect();
-    let path = &args[1];
-    println!("Opening {path}");
+    let mut args = Args::parse();
+    if args.positive_space {
+        args.include = POSITIVE_SPACE.iter().map(|x| x.to_string()).collect();
+        args.exclude.clear();
+    }
+    // Identifies everything that affects the output, for resuming
+    let header = format!(
+        "input={} zoom={:?} layers={:?} include={:?} exclude={:?} attributes={:?}",
+        args.input, args.zoom, args.layers, args.include, args.exclude, args.attributes
+    );
+    let filter = Filter::new(args.layers, &args.include, &args.exclude)?;
+    println!("Opening {}", args.input);
 
-    let mbtiles = Mbtiles::new(path)?;
+    let mbtiles = Mbtiles::new(&args.input)?;
     let mut conn = mbtiles.open_readonly().await?;
 
     let metadata = mbtiles.get_metadata(&mut conn).await?;
-    let bounds = metadata.tilejson.bounds.unwrap();
-    // TODO Could read as the minzoom/maxzoom, which should be the same
-    let zoom = 16_u8;
-    let (x1, y1) = lon_lat_to_tile(bounds.left, bounds.top, zoom.into());
-    let (x2, y2) = lon_lat_to_tile(bounds.right, bounds.bottom, zoom.into());
-    // TODO Something is quite odd here, but the loop below works
-    println!("Reading x = {x1} to {x2}, y = {y1} to {y2}");
-
-    let mut fgb = Some(FgbWriter::create("obstacles", GeometryType::MultiPolygon)?);
-    let progress = ProgressBar::new(((x2 - x1 + 1) * (y2 - y1 + 1)).into()).with_style(ProgressStyle::with_template(
-        "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {human_pos}/{human_len} ({per_sec}, {eta})").unwrap());
+    let zoom = match (
+        args.zoom,
+        metadata.tilejson.minzoom,
+        metadata.tilejson.maxzoom,
+    ) {
+        (Some(zoom), _, _) => zoom,
+        (None, Some(min), Some(max)) if min == max => min,
+        (None, min, max) => {
+            bail!("The metadata has minzoom {min:?} and maxzoom {max:?}, so pass --zoom")
+        }
+    };
 
-    'OUTER: for tile_x in x1..=x2 {
-        for tile_y in y1..=y2 {
-            progress.inc(1);
-            // TODO Still tmp debugging
-            if false && progress.position() == 2_000_000 {
-                break 'OUTER;
-            }
+    let mut tiles = tiles::list_tiles(&mut conn, zoom).await?;
+    if let Some(bounds) = metadata.tilejson.bounds {
+        tiles::check_row_order(
+            &tiles,
+            zoom,
+            Rect::new((bounds.left, bounds.bottom), (bounds.right, bounds.top)),
+        )?;
+    }
+    println!("Found {} tiles at zoom {zoom}", tiles.len());
+    if let Some(ref bbox) = args.bbox {
+        let [left, bottom, right, top] = bbox[..] else {
+            bail!("--bbox needs 4 numbers, not {}", bbox.len());
+        };
+        let (x1, y1) = lon_lat_to_tile(left, top, zoom.into());
+        let (x2, y2) = lon_lat_to_tile(right, bottom, zoom.into());
+        tiles.retain(|(x, y)| (x1..=x2).contains(x) && (y1..=y2).contains(y));
+        println!("{} tiles are in the bbox", tiles.len());
+    }
+    // Nearby tiles are more likely to be stored together
+    tiles.sort();
 
-            match mbtiles.get_tile(&mut conn, zoom, tile_x, tile_y).await {
+    let (mut checkpoint, resumed) = if let Some(ref path) = args.checkpoint {
+        Checkpoint::open(path, &header, &args.attributes, args.resume)?
+    } else {
+        (Checkpoint::none(), Vec::new())
+    };
+    tiles.retain(|tile| !checkpoint.is_done(*tile));
+
+    let mut fgb = FgbWriter::create("obstacles", GeometryType::MultiPolygon)?;
+    for name in &args.attributes {
+        fgb.add_column(name, ColumnType::String, |_, col| {
+            col.nullable = true;
+        });
+    }
+    let mut reassembler = Reassembler::new(&tiles);
+    for fragment in resumed {
+        if let Some(fragment) = reassembler.add(fragment) {
+            write_fragment(&mut fgb, &args.attributes, fragment)?;
+        }
+    }
+
+    let progress = ProgressBar::new(tiles.len() as u64).with_style(ProgressStyle::with_template(
+        "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {human_pos}/{human_len} ({per_sec}, {eta})").unwrap());
+    let mut num_skipped = 0;
+    let mut num_clip_failures = 0;
+    let mut num_errors = 0;
+
+    for batch in tiles.chunks(BATCH_SIZE) {
+        // Reading from sqlite is sequential, but decoding is slow, so do that in parallel
+        let mut raw_tiles = Vec::new();
+        // Tiles that couldn't be read aren't finished, so a resumed run tries them again
+        let mut finished = Vec::new();
+        for (tile_x, tile_y) in batch {
+            match tiles::get_tile(&mut conn, zoom, *tile_x, *tile_y).await {
                 Ok(Some(bytes)) => {
-                    let extent = 4096.0;
-                    let mut out = wrap::WrappedProcessor::new(fgb.take().unwrap(), |x, y| {
-                        pixel_to_lon_lat(x, y, tile_x, tile_y, zoom, extent)
-                    });
-                    let mut decoder = flate2::read::GzDecoder::new(Cursor::new(bytes));
-                    let mut gunzipped = Vec::new();
-                    decoder.read_to_end(&mut gunzipped)?;
-
-                    let tile = geozero::mvt::Tile::decode(Cursor::new(gunzipped))?;
-                    for mut layer in tile.layers {
-                        layer.process(&mut out)?;
-                    }
-                    fgb = Some(out.inner);
+                    raw_tiles.push((*tile_x, *tile_y, bytes));
+                    finished.push((*tile_x, *tile_y));
+                }
+                Ok(None) => {
+                    finished.push((*tile_x, *tile_y));
                 }
-                Ok(None) => {}
                 Err(err) => {
                     println!("Error for {tile_x}, {tile_y}: {err}");
+                    num_errors += 1;
                 }
             }
         }
+        let decoded = raw_tiles
+            .into_par_iter()
+            .map(|(tile_x, tile_y, bytes)| {
+                decode_tile(bytes, tile_x, tile_y, zoom, &filter, &args.attributes)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut fragments = Vec::new();
+        for tile in decoded {
+            num_skipped += tile.num_skipped;
+            num_clip_failures += tile.num_clip_failures;
+            fragments.extend(tile.fragments);
+        }
+        checkpoint.record(&finished, &fragments)?;
+        for fragment in fragments {
+            if let Some(fragment) = reassembler.add(fragment) {
+                write_fragment(&mut fgb, &args.attributes, fragment)?;
+            }
+        }
+        // Features split across tiles are written as soon as all their pieces are here, instead
+        // of keeping them all until the end
+        for fragment in reassembler.tiles_done(batch) {
+            write_fragment(&mut fgb, &args.attributes, fragment)?;
+        }
+        progress.inc(batch.len() as u64);
     }
     progress.finish();
+    println!("Skipped {num_skipped} features filtered by --include or --exclude");
+    if num_errors > 0 {
+        println!("{num_errors} tiles couldn't be read and are missing from the output");
+    }
+    if num_clip_failures > 0 {
+        println!(
+            "{num_clip_failures} fragments couldn't be clipped to their tile and are kept whole"
+        );
+    }
+
+    for fragment in reassembler.finish() {
+        write_fragment(&mut fgb, &args.attributes, fragment)?;
+    }
 
-    println!("Writing");
-    let mut file = BufWriter::new(File::create("out.fgb")?);
-    fgb.take().unwrap().write(&mut file)?;
+    println!("Writing {}", args.output);
+    let mut file = BufWriter::new(File::create(&args.output)?);
+    fgb.write(&mut file)?;
 
     Ok(())
 }
 
+struct DecodedTile {
+    fragments: Vec<Fragment>,
+    num_skipped: usize,
+    num_clip_failures: usize,
+}
+
+fn decode_tile(
+    bytes: Vec<u8>,
+    tile_x: u32,
+    tile_y: u32,
+    zoom: u8,
+    filter: &Filter,
+    attributes: &[String],
+) -> Result<DecodedTile> {
+    let mut decoder = flate2::read::GzDecoder::new(Cursor::new(bytes));
+    let mut gunzipped = Vec::new();
+    decoder.read_to_end(&mut gunzipped)?;
+
+    let tile = geozero::mvt::Tile::decode(Cursor::new(gunzipped))?;
+    let bounds = tile_bounds(tile_x, tile_y, zoom);
+    let mut result = DecodedTile {
+        fragments: Vec::new(),
+        num_skipped: 0,
+        num_clip_failures: 0,
+    };
+    for layer in tile.layers {
+        if !filter.keep_layer(&layer) {
+            continue;
+        }
+        let extent = layer.extent.unwrap_or(4096) as f64;
+        for feature in &layer.features {
+            let properties = filter::properties(&layer, feature);
+            if !filter.keep_feature(&properties) {
+                result.num_skipped += 1;
+                continue;
+            }
+            let mut out = wrap::WrappedProcessor::new(GeoWriter::new(), |x, y| {
+                pixel_to_lon_lat(x, y, tile_x, tile_y, zoom, extent)
+            });
+            feature.process_geom(&mut out)?;
+            let geometry = match out.inner.take_geometry() {
+                Some(Geometry::Polygon(p)) => MultiPolygon::from(p),
+                Some(Geometry::MultiPolygon(mp)) => mp,
+                _ => continue,
+            };
+            let fragment = Fragment {
+                layer: layer.name.clone(),
+                id: feature.id,
+                properties: attributes
+                    .iter()
+                    .map(|name| {
+                        properties
+                            .iter()
+                            .find(|(key, _)| *key == name.as_str())
+                            .map(|(_, value)| value.clone())
+                    })
+                    .collect(),
+                geometry,
+                tile: (tile_x, tile_y),
+                on_seam: false,
+            };
+            result.fragments.extend(reassemble::clip(
+                fragment,
+                bounds,
+                &mut result.num_clip_failures,
+            ));
+        }
+    }
+    Ok(result)
+}
+
+fn write_fragment(fgb: &mut FgbWriter, attributes: &[String], fragment: Fragment) -> Result<()> {
+    let mut result = Ok(());
+    fgb.add_feature_geom(Geometry::MultiPolygon(fragment.geometry), |feat| {
+        for (idx, (name, value)) in attributes.iter().zip(&fragment.properties).enumerate() {
+            if let Some(value) = value {
+                if let Err(err) = feat.property(idx, name, &ColumnValue::String(value)) {
+                    result = Err(err);
+                }
+            }
+        }
+    })?;
+    result?;
+    Ok(())
+}
+
 // Thanks to https://github.com/MilesMcBain/slippymath/blob/master/R/slippymath.R
 // Use https://crates.io/crates/tile-grid or something instead?
 // Alternatively https://wiki.openstreetmap.org/wiki/Slippy_map_tilenames#Python
@@ -95,6 +351,14 @@ fn lon_lat_to_tile(lon: f64, lat: f64, zoom: u32) -> (u32, u32) {
     )
 }
 
+/// The extent of one XYZ tile, without any buffer
+fn tile_bounds(tile_x: u32, tile_y: u32, zoom: u8) -> Rect {
+    Rect::new(
+        pixel_to_lon_lat(0.0, 0.0, tile_x, tile_y, zoom, 1.0),
+        pixel_to_lon_lat(1.0, 1.0, tile_x, tile_y, zoom, 1.0),
+    )
+}
+
 // Via
 // https://github.com/Amyantis/python-vt2geojson/blob/0ab4f10fcf5dc51ce3aa605506dd46f3601292ae/vt2geojson/features.py#L35
 // TODO I think mercantile or tile-grid can replace both of these
@@ -0,0 +1,264 @@
+use std::collections::{HashMap, HashSet};
+
+use geo::{BooleanOps, BoundingRect, Coord, Intersects, MapCoords, MultiPolygon, Rect};
+use rstar::{
+    primitives::{GeomWithData, Rectangle},
+    RTree, RTreeObject,
+};
+use union_find_rs::prelude::{DisjointSets, UnionFind};
+
+use crate::panic::catch_panic;
+
+/// Coordinates this close to a tile edge are moved onto it
+const SNAP_DEGREES: f64 = 1e-9;
+
+/// One feature from one tile
+pub struct Fragment {
+    /// The vector tile layer
+    pub layer: String,
+    /// The feature ID from the vector tile, the same in every tile, but only unique within a layer
+    pub id: Option<u64>,
+    /// Values for each of the output columns
+    pub properties: Vec<Option<String>>,
+    pub geometry: MultiPolygon,
+    pub tile: (u32, u32),
+    /// Touches the edge of its tile, so might have more pieces elsewhere
+    pub on_seam: bool,
+}
+
+/// Clips a fragment to its tile, returning nothing if it's entirely in the tile's buffer. If
+/// clipping fails, the whole fragment is kept and `num_failures` is incremented.
+pub fn clip(
+    mut fragment: Fragment,
+    tile_bounds: Rect,
+    num_failures: &mut usize,
+) -> Option<Fragment> {
+    if !touches_edge(&fragment.geometry, tile_bounds) {
+        return Some(fragment);
+    }
+
+    let Some(clipped) = catch_panic(|| {
+        fragment
+            .geometry
+            .intersection(&MultiPolygon::from(tile_bounds.to_polygon()))
+    }) else {
+        // Still overlapping the neighbouring tiles, so let reassembly merge it with them
+        *num_failures += 1;
+        fragment.on_seam = true;
+        return Some(fragment);
+    };
+    if clipped.0.is_empty() {
+        return None;
+    }
+    // Intersection points along the tile edge might be very slightly off, so the pieces
+    // wouldn't line up exactly with the neighbouring tile
+    fragment.geometry = clipped.map_coords(|c| snap_to_edge(c, tile_bounds));
+    fragment.on_seam = touches_edge(&fragment.geometry, tile_bounds);
+    Some(fragment)
+}
+
+/// Vector tiles include a buffer around each tile, and features crossing a tile boundary are split
+/// into pieces. After clipping every fragment to its tile, this merges the pieces of each feature
+/// back together. Pieces are kept until every tile they could continue into has been processed.
+pub struct Reassembler {
+    /// Tiles that haven't been processed yet
+    remaining: HashSet<(u32, u32)>,
+    on_seams: Vec<Fragment>,
+    num_failures: usize,
+}
+
+impl Reassembler {
+    /// `tiles` will all be processed later
+    pub fn new(tiles: &[(u32, u32)]) -> Self {
+        Self {
+            remaining: tiles.iter().cloned().collect(),
+            on_seams: Vec::new(),
+            num_failures: 0,
+        }
+    }
+
+    /// Returns the fragment if it's complete, otherwise remembers it to merge later
+    pub fn add(&mut self, fragment: Fragment) -> Option<Fragment> {
+        if fragment.on_seam {
+            self.on_seams.push(fragment);
+            None
+        } else {
+            Some(fragment)
+        }
+    }
+
+    /// Call after adding all fragments from some tiles, including tiles that couldn't be read.
+    /// Returns every feature whose pieces can't be in any remaining tile.
+    pub fn tiles_done(&mut self, tiles: &[(u32, u32)]) -> Vec<Fragment> {
+        for tile in tiles {
+            self.remaining.remove(tile);
+        }
+        self.flush()
+    }
+
+    /// Merges all the remaining fragments
+    pub fn finish(mut self) -> Vec<Fragment> {
+        println!(
+            "Reassembling {} fragments left on tile seams",
+            self.on_seams.len()
+        );
+        self.remaining.clear();
+        let output = self.flush();
+        if self.num_failures > 0 {
+            println!(
+                "{} fragments couldn't be merged and are left separate",
+                self.num_failures
+            );
+        }
+        output
+    }
+
+    // Pieces of a feature are found by layer and ID, or for features without one, by touching a
+    // piece in the same layer with the same properties in a different tile. Groups with a piece
+    // next to a remaining tile are kept for later.
+    fn flush(&mut self) -> Vec<Fragment> {
+        let fragments = std::mem::take(&mut self.on_seams);
+
+        let mut sets = DisjointSets::new();
+        for idx in 0..fragments.len() {
+            sets.make_set(idx).unwrap();
+        }
+
+        let mut first_with_id: HashMap<(&str, u64), usize> = HashMap::new();
+        for (idx, f) in fragments.iter().enumerate() {
+            if let Some(id) = f.id {
+                let first = *first_with_id.entry((&f.layer, id)).or_insert(idx);
+                sets.union(&first, &idx).unwrap();
+            }
+        }
+
+        // Only the bounding boxes go in the RTree, to avoid copying the geometry
+        let rtree = RTree::bulk_load(
+            fragments
+                .iter()
+                .enumerate()
+                .filter(|(_, f)| f.id.is_none())
+                .map(|(idx, f)| GeomWithData::new(Rectangle::from_aabb(f.geometry.envelope()), idx))
+                .collect(),
+        );
+        for (idx1, f1) in fragments.iter().enumerate() {
+            if f1.id.is_some() {
+                continue;
+            }
+            for obj in rtree.locate_in_envelope_intersecting(&f1.geometry.envelope()) {
+                let idx2 = obj.data;
+                let f2 = &fragments[idx2];
+                if idx1 < idx2
+                    && f1.tile != f2.tile
+                    && f1.layer == f2.layer
+                    && f1.properties == f2.properties
+                    && f1.geometry.intersects(&f2.geometry)
+                {
+                    sets.union(&idx1, &idx2).unwrap();
+                }
+            }
+        }
+        let sets: Vec<HashSet<usize>> = sets.into_iter().collect();
+
+        let mut fragments: Vec<Option<Fragment>> = fragments.into_iter().map(Some).collect();
+        let mut output = Vec::new();
+        for set in sets {
+            let mut pieces: Vec<Fragment> = set
+                .into_iter()
+                .map(|idx| fragments[idx].take().unwrap())
+                .collect();
+            if pieces.iter().any(|f| self.next_to_remaining(f.tile)) {
+                self.on_seams.extend(pieces);
+                continue;
+            }
+
+            let mut merged = pieces.pop().unwrap();
+            for piece in pieces {
+                if let Some(union) = catch_panic(|| merged.geometry.union(&piece.geometry)) {
+                    merged.geometry = union;
+                } else {
+                    self.num_failures += 1;
+                    output.push(piece);
+                }
+            }
+            output.push(merged);
+        }
+        output
+    }
+
+    // Fragments that couldn't be clipped overlap the tile's buffer on any side, so check all 8
+    // neighbours, not just the ones across edges the fragment touches
+    fn next_to_remaining(&self, (x, y): (u32, u32)) -> bool {
+        (-1..=1).any(|dx| {
+            (-1..=1).any(
+                |dy| match (x.checked_add_signed(dx), y.checked_add_signed(dy)) {
+                    (Some(x), Some(y)) => self.remaining.contains(&(x, y)),
+                    _ => false,
+                },
+            )
+        })
+    }
+}
+
+// After clipping and snapping, coordinates on the seam are exactly on the edge
+fn touches_edge(mp: &MultiPolygon, tile_bounds: Rect) -> bool {
+    let Some(bbox) = mp.bounding_rect() else {
+        return false;
+    };
+    bbox.min().x <= tile_bounds.min().x
+        || bbox.min().y <= tile_bounds.min().y
+        || bbox.max().x >= tile_bounds.max().x
+        || bbox.max().y >= tile_bounds.max().y
+}
+
+fn snap_to_edge(c: Coord, tile_bounds: Rect) -> Coord {
+    let snap = |value: f64, edges: [f64; 2]| {
+        edges
+            .into_iter()
+            .find(|edge| (value - edge).abs() < SNAP_DEGREES)
+            .unwrap_or(value)
+    };
+    Coord {
+        x: snap(c.x, [tile_bounds.min().x, tile_bounds.max().x]),
+        y: snap(c.y, [tile_bounds.min().y, tile_bounds.max().y]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(x: f64, tile: (u32, u32)) -> Fragment {
+        Fragment {
+            layer: "area".to_string(),
+            id: Some(1),
+            properties: Vec::new(),
+            geometry: Rect::new((x, 0.0), (x + 1.0, 1.0)).to_polygon().into(),
+            tile,
+            on_seam: true,
+        }
+    }
+
+    #[test]
+    fn pieces_wait_for_neighbouring_tiles() {
+        let mut reassembler = Reassembler::new(&[(0, 0), (1, 0), (5, 5)]);
+        assert!(reassembler.add(fragment(0.0, (0, 0))).is_none());
+        assert!(reassembler.tiles_done(&[(0, 0)]).is_empty());
+
+        assert!(reassembler.add(fragment(1.0, (1, 0))).is_none());
+        // The same ID in another layer is a different feature
+        let mut other = fragment(1.0, (1, 0));
+        other.layer = "line".to_string();
+        assert!(reassembler.add(other).is_none());
+
+        // (5, 5) isn't next to either piece
+        let output = reassembler.tiles_done(&[(1, 0)]);
+        assert_eq!(output.len(), 2);
+        let merged = output.iter().find(|f| f.layer == "area").unwrap();
+        assert_eq!(
+            merged.geometry.bounding_rect(),
+            Some(Rect::new((0.0, 0.0), (2.0, 1.0)))
+        );
+        assert!(reassembler.finish().is_empty());
+    }
+}
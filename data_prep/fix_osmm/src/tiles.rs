@@ -0,0 +1,105 @@
+use anyhow::{bail, Result};
+use geo::Rect;
+use sqlx::SqliteConnection;
+
+use crate::tile_bounds;
+
+/// Lists every tile that exists at one zoom level, in XYZ coordinates
+pub async fn list_tiles(conn: &mut SqliteConnection, zoom: u8) -> Result<Vec<(u32, u32)>> {
+    let rows: Vec<(i64, i64)> =
+        sqlx::query_as("SELECT tile_column, tile_row FROM tiles WHERE zoom_level = ?")
+            .bind(zoom as i64)
+            .fetch_all(conn)
+            .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(x, row)| (x as u32, flip_row(row as u32, zoom)))
+        .collect())
+}
+
+/// Gets one tile in XYZ coordinates
+pub async fn get_tile(
+    conn: &mut SqliteConnection,
+    zoom: u8,
+    x: u32,
+    y: u32,
+) -> Result<Option<Vec<u8>>> {
+    let row: Option<(Vec<u8>,)> = sqlx::query_as(
+        "SELECT tile_data FROM tiles WHERE zoom_level = ? AND tile_column = ? AND tile_row = ?",
+    )
+    .bind(zoom as i64)
+    .bind(x as i64)
+    .bind(flip_row(y, zoom) as i64)
+    .fetch_optional(conn)
+    .await?;
+    Ok(row.map(|(data,)| data))
+}
+
+/// MBTiles stores rows in TMS order, with 0 at the south, but vector tiles and the rest of this
+/// tool use XYZ order, with 0 at the north. This converts either way.
+fn flip_row(y: u32, zoom: u8) -> u32 {
+    (1 << zoom) - 1 - y
+}
+
+/// Checks that the tiles cover the bounds from the metadata. If the row order were mixed up, the
+/// tiles would be mirrored into the opposite hemisphere.
+pub fn check_row_order(tiles: &[(u32, u32)], zoom: u8, metadata_bounds: Rect) -> Result<()> {
+    let overlap = |tiles: &mut dyn Iterator<Item = (u32, u32)>| {
+        let Some(covered) = tiles
+            .map(|(x, y)| tile_bounds(x, y, zoom))
+            .reduce(|r1, r2| {
+                Rect::new(
+                    (r1.min().x.min(r2.min().x), r1.min().y.min(r2.min().y)),
+                    (r1.max().x.max(r2.max().x), r1.max().y.max(r2.max().y)),
+                )
+            })
+        else {
+            return 0.0;
+        };
+        let width = covered.max().x.min(metadata_bounds.max().x)
+            - covered.min().x.max(metadata_bounds.min().x);
+        let height = covered.max().y.min(metadata_bounds.max().y)
+            - covered.min().y.max(metadata_bounds.min().y);
+        width.max(0.0) * height.max(0.0)
+    };
+
+    let expected = overlap(&mut tiles.iter().cloned());
+    let flipped = overlap(&mut tiles.iter().map(|(x, y)| (*x, flip_row(*y, zoom))));
+    if flipped > expected {
+        bail!(
+            "The tiles only line up with the metadata bounds if rows are in XYZ order, but MBTiles \
+             should use TMS"
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lon_lat_to_tile;
+
+    #[test]
+    fn test_flip_row() {
+        assert_eq!(flip_row(0, 3), 7);
+        assert_eq!(flip_row(7, 3), 0);
+        assert_eq!(flip_row(flip_row(5, 10), 10), 5);
+    }
+
+    #[test]
+    fn test_check_row_order() {
+        let zoom = 10;
+        // A few tiles around central London, in XYZ order
+        let (x, y) = lon_lat_to_tile(-0.1, 51.5, zoom as u32);
+        let tiles = vec![(x, y), (x + 1, y), (x, y + 1)];
+        let london = Rect::new((-0.5, 51.2), (0.3, 51.8));
+
+        assert!(check_row_order(&tiles, zoom, london).is_ok());
+        // In TMS order, they'd be mirrored into the southern hemisphere
+        let flipped: Vec<(u32, u32)> = tiles
+            .iter()
+            .map(|(x, y)| (*x, flip_row(*y, zoom)))
+            .collect();
+        assert!(check_row_order(&flipped, zoom, london).is_err());
+    }
+}
@@ -0,0 +1,23 @@
+use geo::Polygon;
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::union::{cascading_union, Failures};
+use crate::PROGRESS_STYLE;
+
+/// Unions all touching or overlapping parcels, returning each resulting polygon separately. Uses
+/// the dissolver's cascading union, with the same fallbacks when a union fails.
+pub fn dissolve(polygons: Vec<Polygon>, failures: &Failures) -> Vec<Polygon> {
+    let progress = ProgressBar::new(polygons.len() as u64)
+        .with_style(ProgressStyle::with_template(PROGRESS_STYLE).unwrap());
+    let result = cascading_union(
+        polygons
+            .into_iter()
+            .enumerate()
+            .map(|(idx, p)| (Some(idx), p))
+            .collect(),
+        &progress,
+        failures,
+    );
+    progress.finish();
+    result
+}
@@ -0,0 +1,88 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use anyhow::{bail, Result};
+use geo::{Coord, LineString, Polygon};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+/// The file inside each zip with the parcels
+const GML_FILE: &str = "Land_Registry_Cadastral_Parcels.gml";
+
+/// Reads every parcel polygon from an INSPIRE zip file, in British National Grid. Everything
+/// except the geometry is ignored.
+pub fn read_parcels(zip_path: &str) -> Result<Vec<Polygon>> {
+    let mut archive = zip::ZipArchive::new(File::open(zip_path)?)?;
+    let gml = BufReader::new(archive.by_name(GML_FILE)?);
+    let mut reader = Reader::from_reader(gml);
+    reader.config_mut().trim_text(true);
+
+    let mut polygons = Vec::new();
+    let mut exterior: Option<LineString> = None;
+    let mut interiors = Vec::new();
+    // Which ring the next posList belongs to
+    let mut in_interior = false;
+    let mut in_pos_list = false;
+
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) => match e.local_name().as_ref() {
+                b"exterior" => {
+                    in_interior = false;
+                }
+                b"interior" => {
+                    in_interior = true;
+                }
+                b"posList" => {
+                    in_pos_list = true;
+                }
+                _ => {}
+            },
+            Event::Text(e) if in_pos_list => {
+                let ring = parse_pos_list(&e.unescape()?)?;
+                if in_interior {
+                    interiors.push(ring);
+                } else {
+                    exterior = Some(ring);
+                }
+            }
+            Event::End(e) => match e.local_name().as_ref() {
+                b"posList" => {
+                    in_pos_list = false;
+                }
+                b"Polygon" => {
+                    let Some(exterior) = exterior.take() else {
+                        bail!("{zip_path} has a polygon without an exterior");
+                    };
+                    polygons.push(Polygon::new(exterior, std::mem::take(&mut interiors)));
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(polygons)
+}
+
+// Like "x1 y1 x2 y2 ..."
+fn parse_pos_list(text: &str) -> Result<LineString> {
+    let numbers = text
+        .split_whitespace()
+        .map(|x| x.parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()?;
+    if numbers.len() % 2 != 0 {
+        bail!("posList has an odd number of values: {text}");
+    }
+    Ok(LineString::new(
+        numbers
+            .chunks_exact(2)
+            .map(|pair| Coord {
+                x: pair[0],
+                y: pair[1],
+            })
+            .collect(),
+    ))
+}
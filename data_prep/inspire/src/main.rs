@@ -0,0 +1,194 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use flatgeobuf::{
+    ColumnType, FallibleStreamingIterator, FgbCrs, FgbReader, FgbWriter, FgbWriterOptions,
+    GeometryType, GeozeroGeometry,
+};
+use geo::{Coord, MapCoordsInPlace, Polygon};
+use geozero::{ColumnValue, FeatureProperties, PropertyProcessor};
+use proj4rs::Proj;
+
+mod dissolve;
+mod gml;
+#[path = "../../shared/panic.rs"]
+mod panic;
+#[path = "../../shared/union.rs"]
+mod union;
+
+static PROGRESS_STYLE: &str =
+    "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {human_pos}/{human_len} ({per_sec}, {eta})";
+
+/// Converts INSPIRE land parcels, from the zip files at
+/// https://use-land-property-data.service.gov.uk/datasets/inspire/download, into one FlatGeobuf
+/// file. Parcels in each area are dissolved together and transformed to WGS84. This does the same
+/// as `inspire_one_area.sh` and `merge_files`, without needing ogr2ogr or mapshaper.
+#[derive(Parser)]
+struct Args {
+    /// Zip files for each area, like `Adur_District_Council.zip`
+    inputs: Vec<String>,
+
+    /// The FlatGeobuf file to add to. If an area is already in the file, its polygons are
+    /// replaced.
+    #[arg(long, default_value = "out.fgb")]
+    output: String,
+
+    /// Where to write any parcels that couldn't be unioned. `context` is the area, and `input_idx`
+    /// counts parcels within its file.
+    #[arg(long, default_value = "failures.geojson")]
+    failures: String,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let mut failures = union::Failures::default();
+    let mut areas = Vec::new();
+    for (idx, path) in args.inputs.iter().enumerate() {
+        println!("Reading {path} ({} / {})", idx + 1, args.inputs.len());
+        let area = area_name(path);
+        let parcels = gml::read_parcels(path)?;
+        println!("Dissolving {} parcels", parcels.len());
+        failures.set_context(area.clone());
+        let mut polygons = dissolve::dissolve(parcels, &failures);
+        bng_to_wgs84(&mut polygons)?;
+        areas.push((area, polygons));
+    }
+
+    append(&args.output, areas)?;
+    failures.write(&args.failures, bng_to_wgs84)
+}
+
+// Like "Adur_District_Council"
+fn area_name(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string())
+}
+
+fn bng_to_wgs84(polygons: &mut [Polygon]) -> Result<()> {
+    let bng = Proj::from_epsg_code(27700).unwrap();
+    let wgs84 = Proj::from_epsg_code(4326).unwrap();
+    for p in polygons {
+        if let Err(err) = proj4rs::transform::transform(&bng, &wgs84, p) {
+            bail!("Couldn't transform to WGS84: {err}");
+        }
+        // proj4rs uses radians for geographic coordinates
+        p.map_coords_in_place(|c| Coord {
+            x: c.x.to_degrees(),
+            y: c.y.to_degrees(),
+        });
+    }
+    Ok(())
+}
+
+// FlatGeobuf can't be appended to in place, so copy everything from the existing file, except
+// areas being replaced
+fn append(path: &str, areas: Vec<(String, Vec<Polygon>)>) -> Result<()> {
+    let mut fgb = FgbWriter::create_with_options(
+        "inspire",
+        GeometryType::Polygon,
+        FgbWriterOptions {
+            write_index: true,
+            crs: FgbCrs {
+                code: 4326,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    )?;
+    fgb.add_column("area", ColumnType::String, |_, col| {
+        col.nullable = true;
+    });
+
+    let replacing: HashSet<&str> = areas.iter().map(|(area, _)| area.as_str()).collect();
+    if Path::new(path).exists() {
+        println!("Copying other areas from {path}");
+        let reader = FgbReader::open(BufReader::new(File::open(path)?))?;
+        // Without this column, rerunning an area would duplicate its polygons
+        let has_area = reader
+            .header()
+            .columns()
+            .is_some_and(|columns| columns.iter().any(|col| col.name() == "area"));
+        if !has_area {
+            bail!(
+                "{path} has no area column, so areas in it can't be replaced. Files from \
+                 merge_files don't have one; rebuild it from all the zip files with this tool."
+            );
+        }
+        let mut features = reader.select_all()?;
+        while let Some(feature) = features.next()? {
+            let mut area = AreaProperty(String::new());
+            feature.process_properties(&mut area)?;
+            let area = area.0;
+            if replacing.contains(area.as_str()) {
+                continue;
+            }
+            let mut geom = geozero::geo_types::GeoWriter::new();
+            feature.process_geom(&mut geom)?;
+            match geom.take_geometry() {
+                Some(geo::Geometry::Polygon(p)) => {
+                    add_polygon(&mut fgb, p, &area)?;
+                }
+                Some(geo::Geometry::MultiPolygon(mp)) => {
+                    for p in mp {
+                        add_polygon(&mut fgb, p, &area)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    for (area, polygons) in areas {
+        println!("Adding {} polygons for {area}", polygons.len());
+        for p in polygons {
+            add_polygon(&mut fgb, p, &area)?;
+        }
+    }
+
+    // Don't clobber the output until the new version is complete
+    let tmp_path = format!("{path}.tmp");
+    println!("Writing {path}");
+    fgb.write(&mut BufWriter::new(File::create(&tmp_path)?))?;
+    std::fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+fn add_polygon(fgb: &mut FgbWriter, p: Polygon, area: &str) -> Result<()> {
+    let mut result = Ok(());
+    fgb.add_feature_geom(geo::Geometry::Polygon(p), |feat| {
+        if !area.is_empty() {
+            result = feat
+                .property(0, "area", &ColumnValue::String(area))
+                .map(|_| ());
+        }
+    })?;
+    result?;
+    Ok(())
+}
+
+struct AreaProperty(String);
+
+impl PropertyProcessor for AreaProperty {
+    fn property(
+        &mut self,
+        _: usize,
+        name: &str,
+        value: &ColumnValue,
+    ) -> geozero::error::Result<bool> {
+        if name != "area" {
+            return Ok(false);
+        }
+        if let ColumnValue::String(x) = value {
+            self.0 = x.to_string();
+        }
+        // Stop reading properties
+        Ok(true)
+    }
+}
@@ -11,6 +11,12 @@ done
 
 # Wait for pueue to finish. inspire/ will contain individual geojson files. Then:
 #
-# cd merge_files; cargo run --release ../inspire/*
+# cd merge_files; cargo run --release ../inspire
 #
-# to produce one final fgb
+# to produce one final fgb.
+#
+# Alternatively, just download the zip files, then
+#
+# cd inspire; cargo run --release ../*.zip
+#
+# does the conversion, dissolving, and merging without ogr2ogr or mapshaper.
@@ -1,28 +1,106 @@
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::io::BufWriter;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use clap::Parser;
 use flatgeobuf::*;
-use geozero::geojson::GeoJsonReader;
-use geozero::GeozeroDatasource;
+use geozero::{ColumnValue, PropertyProcessor};
+
+use crate::validate::Validator;
+
+#[path = "../../shared/properties.rs"]
+mod properties;
+mod read;
+mod validate;
+#[path = "../../shared/validity.rs"]
+mod validity;
+
+/// This converts a bunch of GeoJSON, newline-delimited GeoJSON, or FlatGeobuf files into one
+/// flatgeobuffer file of polygons, optionally keeping some properties. ogr2ogr doesn't reasonably
+/// handle multiple input files. Along the way, it checks for broken polygons and removes
+/// duplicates, then prints a summary.
+#[derive(Parser)]
+struct Args {
+    /// Files with polygons or multipolygons, or directories containing them. The format is
+    /// detected from the extension: `.geojson`, `.geojsonl`, or `.fgb`.
+    inputs: Vec<String>,
+
+    /// A file listing more inputs, one per line. Use this for thousands of files.
+    #[arg(long)]
+    file_list: Option<String>,
+
+    #[arg(long, default_value = "out.fgb")]
+    output: String,
+
+    /// Feature properties to keep in the output, comma-separated. Every value is stored as a
+    /// string.
+    #[arg(long, value_delimiter = ',')]
+    attributes: Vec<String>,
+
+    /// Skip polygons that intersect themselves, instead of just reporting them
+    #[arg(long)]
+    drop_invalid: bool,
+}
 
-/// This just converts a bunch of GeoJSON files into one flatgeobuffer file. ogr2ogr doesn't
-/// reasonably handle multiple input files.
 fn main() -> Result<()> {
-    // Use Unknown to handle both polygons and multipolygons
-    let mut fgb = FgbWriter::create("obstacles", GeometryType::Unknown)?;
-
-    let paths: Vec<_> = std::env::args().skip(1).collect();
-    for (idx, path) in paths.iter().enumerate() {
-        println!("Reading {path} ({} / {})", idx + 1, paths.len());
-        let mut file = BufReader::new(File::open(path)?);
-        let mut reader = GeoJsonReader(&mut file);
-        reader.process(&mut fgb)?;
+    let args = Args::parse();
+
+    let mut fgb = FgbWriter::create("obstacles", GeometryType::Polygon)?;
+    for name in &args.attributes {
+        fgb.add_column(name, ColumnType::String, |_, col| {
+            col.nullable = true;
+        });
     }
 
-    println!("Writing");
-    let mut file = BufWriter::new(File::create("out.fgb")?);
+    let inputs = read::find_inputs(&args.inputs, args.file_list.as_deref())?;
+    if inputs.is_empty() {
+        bail!("No input files");
+    }
+
+    let mut validator = Validator::new(args.drop_invalid);
+    for (idx, path) in inputs.iter().enumerate() {
+        println!("Reading {path} ({} / {})", idx + 1, inputs.len());
+        read::read_features(path, |mut f| {
+            let Some(geometry) = f.geometry.take() else {
+                return Ok(());
+            };
+            for polygon in validator.check(geometry) {
+                let mut result = Ok(());
+                fgb.add_feature_geom(geo::Geometry::Polygon(polygon), |feat| {
+                    result = write_attributes(feat, &args.attributes, &f);
+                })?;
+                result?;
+            }
+            Ok(())
+        })?;
+    }
+    validator.report.print();
+
+    println!("Writing {}", args.output);
+    let mut file = BufWriter::new(File::create(&args.output)?);
     fgb.write(&mut file)?;
 
     Ok(())
 }
+
+// Writes the attributes, in the order of the output columns, for one feature
+fn write_attributes<P: PropertyProcessor>(
+    out: &mut P,
+    attributes: &[String],
+    f: &geojson::Feature,
+) -> geozero::error::Result<()> {
+    for (idx, name) in attributes.iter().enumerate() {
+        let Some(value) = f.property(name) else {
+            continue;
+        };
+        if value.is_null() {
+            continue;
+        }
+        let value = match value.as_str() {
+            Some(x) => x.to_string(),
+            None => value.to_string(),
+        };
+        out.property(idx, name, &ColumnValue::String(&value))?;
+    }
+    Ok(())
+}
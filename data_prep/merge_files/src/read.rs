@@ -0,0 +1,108 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use flatgeobuf::{FallibleStreamingIterator, FgbReader, GeozeroGeometry};
+use geozero::FeatureProperties;
+use serde_json::Map;
+
+use crate::properties::JsonProperties;
+
+/// Expands the input arguments and the optional file list into every file to read. Directories
+/// are searched for supported files, non-recursively, in sorted order.
+pub fn find_inputs(args: &[String], file_list: Option<&str>) -> Result<Vec<String>> {
+    let mut paths: Vec<String> = args.to_vec();
+    // Shells limit the length of arguments, so long lists are read from a file instead
+    if let Some(list) = file_list {
+        for line in BufReader::new(File::open(list)?).lines() {
+            let line = line?;
+            let line = line.trim();
+            if !line.is_empty() {
+                paths.push(line.to_string());
+            }
+        }
+    }
+
+    let mut inputs = Vec::new();
+    for path in paths {
+        if Path::new(&path).is_dir() {
+            let mut files = Vec::new();
+            for entry in std::fs::read_dir(&path)? {
+                let file = entry?.path().to_string_lossy().to_string();
+                if Format::detect(&file).is_some() {
+                    files.push(file);
+                }
+            }
+            files.sort();
+            inputs.extend(files);
+        } else {
+            inputs.push(path);
+        }
+    }
+    Ok(inputs)
+}
+
+enum Format {
+    GeoJson,
+    GeoJsonSeq,
+    FlatGeobuf,
+}
+
+impl Format {
+    fn detect(path: &str) -> Option<Self> {
+        let extension = Path::new(path).extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "geojson" | "json" => Some(Self::GeoJson),
+            "geojsonl" | "geojsons" | "geojsonseq" | "jsonl" | "ndjson" => Some(Self::GeoJsonSeq),
+            "fgb" => Some(Self::FlatGeobuf),
+            _ => None,
+        }
+    }
+}
+
+/// Calls `handle` on every feature in a GeoJSON, newline-delimited GeoJSON, or FlatGeobuf file
+pub fn read_features<F: FnMut(geojson::Feature) -> Result<()>>(
+    path: &str,
+    mut handle: F,
+) -> Result<()> {
+    let Some(format) = Format::detect(path) else {
+        bail!("Don't know the format of {path}");
+    };
+    let file = BufReader::new(File::open(path)?);
+    match format {
+        Format::GeoJson => {
+            for f in geojson::FeatureReader::from_reader(file).features() {
+                handle(f?)?;
+            }
+        }
+        Format::GeoJsonSeq => {
+            for line in file.lines() {
+                let line = line?;
+                // RFC 8142 starts each record with a record separator
+                let line = line.trim_start_matches('\x1e').trim();
+                if !line.is_empty() {
+                    handle(serde_json::from_str(line)?)?;
+                }
+            }
+        }
+        Format::FlatGeobuf => {
+            let mut features = FgbReader::open(file)?.select_all()?;
+            while let Some(feature) = features.next()? {
+                let mut properties = JsonProperties(Map::new());
+                feature.process_properties(&mut properties)?;
+
+                let mut geometry = geozero::geo_types::GeoWriter::new();
+                feature.process_geom(&mut geometry)?;
+                let Some(geometry) = geometry.take_geometry() else {
+                    continue;
+                };
+
+                let mut f = geojson::Feature::from(geojson::Geometry::from(&geometry));
+                f.properties = Some(properties.0);
+                handle(f)?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,194 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+
+use geo::{Coord, LineString, Polygon};
+
+use crate::validity::self_intersects;
+
+/// Round coordinates to this many degrees when looking for duplicates, about 1cm
+const DUPLICATE_PRECISION: f64 = 1e-7;
+
+/// Checks and cleans up polygons from all input files, counting problems for a final report
+pub struct Validator {
+    drop_invalid: bool,
+    // Hashes of every polygon kept so far
+    seen: HashSet<u64>,
+    pub report: Report,
+}
+
+#[derive(Default)]
+pub struct Report {
+    pub features: usize,
+    pub not_polygons: usize,
+    pub multipolygons_exploded: usize,
+    pub unclosed_rings: usize,
+    pub degenerate_rings: usize,
+    pub self_intersecting: usize,
+    pub duplicates: usize,
+    pub polygons_written: usize,
+}
+
+impl Validator {
+    /// If `drop_invalid` is set, self-intersecting polygons are skipped. Otherwise they're just
+    /// counted.
+    pub fn new(drop_invalid: bool) -> Self {
+        Self {
+            drop_invalid,
+            seen: HashSet::new(),
+            report: Report::default(),
+        }
+    }
+
+    /// Returns the polygons to keep from one feature
+    pub fn check(&mut self, geometry: geojson::Geometry) -> Vec<Polygon> {
+        self.report.features += 1;
+        let polygons = match geometry.value {
+            geojson::Value::Polygon(rings) => vec![rings],
+            geojson::Value::MultiPolygon(polygons) => {
+                self.report.multipolygons_exploded += 1;
+                polygons
+            }
+            _ => {
+                self.report.not_polygons += 1;
+                return Vec::new();
+            }
+        };
+
+        let mut output = Vec::new();
+        for rings in polygons {
+            let Some(polygon) = self.make_polygon(rings) else {
+                continue;
+            };
+            if self_intersects(&polygon) {
+                self.report.self_intersecting += 1;
+                if self.drop_invalid {
+                    continue;
+                }
+            }
+            // Areas are split along boundaries, and polygons there can appear in both files
+            if !self.seen.insert(hash_polygon(&polygon)) {
+                self.report.duplicates += 1;
+                continue;
+            }
+            self.report.polygons_written += 1;
+            output.push(polygon);
+        }
+        output
+    }
+
+    // geo closes rings automatically, so check before converting. Returns nothing if the exterior
+    // is degenerate.
+    fn make_polygon(&mut self, rings: Vec<Vec<Vec<f64>>>) -> Option<Polygon> {
+        let mut linestrings = Vec::new();
+        for ring in rings {
+            if ring.first() != ring.last() {
+                self.report.unclosed_rings += 1;
+            }
+            let ls: LineString = ring
+                .into_iter()
+                .map(|pt| Coord { x: pt[0], y: pt[1] })
+                .collect();
+            linestrings.push(ls);
+        }
+
+        let mut linestrings = linestrings.into_iter();
+        let mut exterior = linestrings.next()?;
+        exterior.close();
+        if exterior.0.len() < 4 {
+            self.report.degenerate_rings += 1;
+            return None;
+        }
+        let mut interiors = Vec::new();
+        for mut ls in linestrings {
+            ls.close();
+            if ls.0.len() < 4 {
+                self.report.degenerate_rings += 1;
+            } else {
+                interiors.push(ls);
+            }
+        }
+        Some(Polygon::new(exterior, interiors))
+    }
+}
+
+impl Report {
+    pub fn print(&self) {
+        println!("Read {} features", self.features);
+        println!("- {} weren't polygons and were skipped", self.not_polygons);
+        println!(
+            "- {} multipolygons were split into polygons",
+            self.multipolygons_exploded
+        );
+        println!("- {} rings weren't closed", self.unclosed_rings);
+        println!(
+            "- {} rings had fewer than 3 points and were skipped",
+            self.degenerate_rings
+        );
+        println!("- {} polygons intersect themselves", self.self_intersecting);
+        println!("- {} duplicate polygons were skipped", self.duplicates);
+        println!("Wrote {} polygons", self.polygons_written);
+    }
+}
+
+// Two copies of a polygon might start their rings at different points or go in different
+// directions, so normalize the exterior first
+fn hash_polygon(polygon: &Polygon) -> u64 {
+    let mut coords: Vec<(i64, i64)> = polygon
+        .exterior()
+        .0
+        .iter()
+        .skip(1)
+        .map(|c| {
+            (
+                (c.x / DUPLICATE_PRECISION).round() as i64,
+                (c.y / DUPLICATE_PRECISION).round() as i64,
+            )
+        })
+        .collect();
+    let start = coords
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, c)| **c)
+        .map(|(idx, _)| idx)
+        .unwrap_or(0);
+    coords.rotate_left(start);
+    if coords.len() > 2 && coords[1] > coords[coords.len() - 1] {
+        coords[1..].reverse();
+    }
+
+    let mut hasher = DefaultHasher::new();
+    coords.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(coords: &[(f64, f64)]) -> Polygon {
+        let mut coords = coords.to_vec();
+        coords.push(coords[0]);
+        Polygon::new(LineString::from(coords), Vec::new())
+    }
+
+    #[test]
+    fn test_hash_polygon() {
+        let corners = [(0.0, 0.0), (0.001, 0.0), (0.001, 0.001), (0.0, 0.001)];
+        let original = hash_polygon(&square(&corners));
+
+        // Starting from a different corner
+        let mut rotated = corners;
+        rotated.rotate_left(2);
+        assert_eq!(hash_polygon(&square(&rotated)), original);
+
+        // Going the other way around
+        let mut reversed = corners;
+        reversed.reverse();
+        assert_eq!(hash_polygon(&square(&reversed)), original);
+
+        // A different square
+        let moved = corners.map(|(x, y)| (x + 0.001, y));
+        assert_ne!(hash_polygon(&square(&moved)), original);
+    }
+}
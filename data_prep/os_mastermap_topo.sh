@@ -9,11 +9,11 @@ set -x
 # TODO Work with the UK-wide extract
 INPUT=~/Downloads/ordnance_survey_downloads_old/Data/OSMasterMapTopography_6471149_topographic_area.gpkg
 
-# Extract relevant polygons from the gpkg, drop all properties, and fix the coordinate system
-ogr2ogr v1.geojson -t_srs EPSG:4326 $INPUT -sql 'SELECT geometry FROM topographic_area WHERE style_description NOT IN ("Road Or Track Fill", "Roadside Manmade Fill", "Path Fill", "Traffic Calming Fill")'
+# Extract relevant polygons from the gpkg, keep the obstacle class, and fix the coordinate system
+ogr2ogr v1.geojson -t_srs EPSG:4326 $INPUT -sql 'SELECT geometry, descriptive_group FROM topographic_area WHERE style_description NOT IN ("Road Or Track Fill", "Roadside Manmade Fill", "Path Fill", "Traffic Calming Fill")'
 
-# Merge adjacent polygons into one for performance. Explode the resulting multipolygon into many polygons
-mapshaper v1.geojson -dissolve -explode -o v2.geojson format=geojson geojson-type=FeatureCollection
+# Merge adjacent polygons of the same class into one for performance. Explode the resulting multipolygon into many polygons
+mapshaper v1.geojson -dissolve descriptive_group -explode -o v2.geojson format=geojson geojson-type=FeatureCollection
 
 # Convert to flatgeobuf
 ogr2ogr out.fgb v2.geojson
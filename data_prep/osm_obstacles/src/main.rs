@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+
+use anyhow::Result;
+use clap::Parser;
+use flatgeobuf::{ColumnType, FgbCrs, FgbWriter, FgbWriterOptions, GeometryType};
+use geo::{Contains, Coord, LineString, Polygon};
+use geozero::{ColumnValue, PropertyProcessor};
+use osm_reader::{Element, NodeID, OsmID, WayID};
+use utils::Tags;
+
+/// Builds obstacle polygons from OpenStreetMap: buildings, water, and walls, fences, and hedges
+/// buffered into thin polygons. Use this where INSPIRE and OS MasterMap data aren't available.
+#[derive(Parser)]
+struct Args {
+    /// An .osm.pbf or .osm.xml file
+    input: String,
+
+    #[arg(long, default_value = "out.fgb")]
+    output: String,
+
+    /// How thick to make walls, in meters
+    #[arg(long, default_value_t = 0.3)]
+    wall_width: f64,
+
+    /// How thick to make fences, in meters
+    #[arg(long, default_value_t = 0.1)]
+    fence_width: f64,
+
+    /// How thick to make hedges, in meters
+    #[arg(long, default_value_t = 1.0)]
+    hedge_width: f64,
+}
+
+#[derive(Clone, Copy)]
+enum Class {
+    Building,
+    Water,
+    Wall,
+    Fence,
+    Hedge,
+}
+
+impl Class {
+    fn name(self) -> &'static str {
+        match self {
+            Class::Building => "building",
+            Class::Water => "water",
+            Class::Wall => "wall",
+            Class::Fence => "fence",
+            Class::Hedge => "hedge",
+        }
+    }
+
+    // For closed ways and multipolygon relations
+    fn area(tags: &Tags) -> Option<Self> {
+        if tags.has("building") && !tags.is("building", "no") {
+            return Some(Class::Building);
+        }
+        if tags.is("natural", "water")
+            || tags.is("waterway", "riverbank")
+            || tags.is("landuse", "reservoir")
+            || tags.is("landuse", "basin")
+        {
+            return Some(Class::Water);
+        }
+        None
+    }
+
+    fn line(tags: &Tags) -> Option<Self> {
+        match tags.get("barrier").map(|x| x.as_str()) {
+            Some("wall" | "retaining_wall" | "city_wall") => Some(Class::Wall),
+            Some("fence" | "railing" | "guard_rail") => Some(Class::Fence),
+            Some("hedge") => Some(Class::Hedge),
+            _ => None,
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    println!("Reading {}", args.input);
+    let obstacles = Obstacles::read(&std::fs::read(&args.input)?)?;
+
+    let mut output: Vec<(Polygon, Class, String)> = obstacles.polygons;
+    println!(
+        "Found {} areas and {} barriers",
+        output.len(),
+        obstacles.barriers.len()
+    );
+    for (ls, class, id) in obstacles.barriers {
+        let width = match class {
+            Class::Wall => args.wall_width,
+            Class::Hedge => args.hedge_width,
+            _ => args.fence_width,
+        };
+        for p in buffer_line(&ls, width) {
+            output.push((p, class, id.clone()));
+        }
+    }
+
+    println!("Writing {} polygons to {}", output.len(), args.output);
+    let mut fgb = FgbWriter::create_with_options(
+        "obstacles",
+        GeometryType::Polygon,
+        FgbWriterOptions {
+            write_index: true,
+            crs: FgbCrs {
+                code: 4326,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    )?;
+    fgb.add_column("class", ColumnType::String, |_, _| {});
+    fgb.add_column("osm_id", ColumnType::String, |_, _| {});
+    for (p, class, id) in output {
+        let mut result = Ok(());
+        fgb.add_feature_geom(geo::Geometry::Polygon(p), |feat| {
+            result = feat
+                .property(0, "class", &ColumnValue::String(class.name()))
+                .and_then(|_| feat.property(1, "osm_id", &ColumnValue::String(&id)))
+                .map(|_| ());
+        })?;
+        result?;
+    }
+    let mut file = BufWriter::new(File::create(&args.output)?);
+    fgb.write(&mut file)?;
+
+    Ok(())
+}
+
+struct Obstacles {
+    /// In WGS84, with the class and OSM ID
+    polygons: Vec<(Polygon, Class, String)>,
+    barriers: Vec<(LineString, Class, String)>,
+}
+
+impl Obstacles {
+    // Relations come after the ways they use, so the first pass finds the building and water
+    // relations, and the second only keeps the ways they need
+    fn read(input_bytes: &[u8]) -> Result<Self> {
+        let mut relations = Vec::new();
+        osm_reader::parse(input_bytes, |elem| {
+            if let Element::Relation {
+                id, tags, members, ..
+            } = elem
+            {
+                let tags: Tags = tags.into();
+                if tags.is("type", "multipolygon") {
+                    if let Some(class) = Class::area(&tags) {
+                        relations.push((id, class, members));
+                    }
+                }
+            }
+        })?;
+        let mut member_ways: HashMap<WayID, Vec<Coord>> = relations
+            .iter()
+            .flat_map(|(_, _, members)| members)
+            .filter_map(|(_, member)| match member {
+                OsmID::Way(way) => Some((*way, Vec::new())),
+                _ => None,
+            })
+            .collect();
+
+        let mut obstacles = Self {
+            polygons: Vec::new(),
+            barriers: Vec::new(),
+        };
+        let mut nodes: HashMap<NodeID, Coord> = HashMap::new();
+        osm_reader::parse(input_bytes, |elem| match elem {
+            Element::Node { id, lon, lat, .. } => {
+                nodes.insert(id, Coord { x: lon, y: lat });
+            }
+            Element::Way {
+                id, node_ids, tags, ..
+            } => {
+                let Some(pts) = node_ids
+                    .iter()
+                    .map(|n| nodes.get(n).cloned())
+                    .collect::<Option<Vec<_>>>()
+                else {
+                    return;
+                };
+                if let Some(member) = member_ways.get_mut(&id) {
+                    *member = pts.clone();
+                }
+                obstacles.way(id, &node_ids, pts, &tags.into());
+            }
+            _ => {}
+        })?;
+
+        for (id, class, members) in relations {
+            let rings = |role: &str| {
+                join_rings(
+                    members
+                        .iter()
+                        .filter(|(r, _)| r == role || (role == "outer" && r.is_empty()))
+                        .filter_map(|(_, member)| match member {
+                            OsmID::Way(way) => member_ways.get(way).cloned(),
+                            _ => None,
+                        })
+                        .filter(|pts| !pts.is_empty())
+                        .collect(),
+                )
+            };
+            let mut polygons: Vec<Polygon> = rings("outer")
+                .into_iter()
+                .map(|ring| Polygon::new(ring, Vec::new()))
+                .collect();
+            // Each hole goes in the outer ring containing it
+            for inner in rings("inner") {
+                if let Some(p) = polygons
+                    .iter_mut()
+                    .find(|p| inner.coords().any(|c| p.contains(c)))
+                {
+                    p.interiors_push(inner);
+                }
+            }
+            for p in polygons {
+                obstacles
+                    .polygons
+                    .push((p, class, format!("relation/{}", id.0)));
+            }
+        }
+        Ok(obstacles)
+    }
+
+    fn way(&mut self, id: WayID, node_ids: &[NodeID], pts: Vec<Coord>, tags: &Tags) {
+        let closed = node_ids.len() >= 4 && node_ids[0] == node_ids[node_ids.len() - 1];
+
+        if closed {
+            if let Some(class) = Class::area(tags) {
+                self.polygons.push((
+                    Polygon::new(LineString::new(pts), Vec::new()),
+                    class,
+                    format!("way/{}", id.0),
+                ));
+                return;
+            }
+        }
+        if let Some(class) = Class::line(tags) {
+            self.barriers
+                .push((LineString::new(pts), class, format!("way/{}", id.0)));
+        }
+    }
+}
+
+// Joins the pieces of multipolygon rings end-to-end. Pieces that don't form a closed ring are
+// dropped.
+fn join_rings(mut pieces: Vec<Vec<Coord>>) -> Vec<LineString> {
+    let mut rings = Vec::new();
+    while let Some(mut ring) = pieces.pop() {
+        loop {
+            if ring.len() >= 4 && ring[0] == ring[ring.len() - 1] {
+                rings.push(LineString::new(ring));
+                break;
+            }
+            let end = ring[ring.len() - 1];
+            let Some(idx) = pieces
+                .iter()
+                .position(|piece| piece[0] == end || piece[piece.len() - 1] == end)
+            else {
+                break;
+            };
+            let mut next = pieces.remove(idx);
+            if next[0] != end {
+                next.reverse();
+            }
+            ring.extend(next.into_iter().skip(1));
+        }
+    }
+    rings
+}
+
+// Turns a WGS84 line into polygons `width_meters` thick, by offsetting it to either side. The
+// offset sides would cross at sharp corners, so the line is split into separate polygons there.
+fn buffer_line(ls: &LineString, width_meters: f64) -> Vec<Polygon> {
+    if ls.0.is_empty() {
+        return Vec::new();
+    }
+    // Over the length of one way, treat degrees as flat
+    let meters_per_degree_lat = 111_320.0;
+    let avg_lat = ls.0.iter().map(|c| c.y).sum::<f64>() / (ls.0.len() as f64);
+    let meters_per_degree_lon = meters_per_degree_lat * avg_lat.to_radians().cos();
+
+    let mut pts: Vec<Coord> = Vec::new();
+    for c in &ls.0 {
+        let pt = Coord {
+            x: c.x * meters_per_degree_lon,
+            y: c.y * meters_per_degree_lat,
+        };
+        if pts.last() != Some(&pt) {
+            pts.push(pt);
+        }
+    }
+    // The two ends of a closed way would touch, so split it in the middle too
+    let closed = pts.len() > 3 && pts[0] == pts[pts.len() - 1];
+    let middle = pts.len() / 2;
+
+    let mut polygons = Vec::new();
+    let mut run: Vec<Coord> = Vec::new();
+    for (idx, pt) in pts.into_iter().enumerate() {
+        let n = run.len();
+        if n >= 2 && (is_sharp_turn(run[n - 2], run[n - 1], pt) || (closed && idx == middle + 1)) {
+            polygons.push(offset_polygon(&run, width_meters / 2.0));
+            run = vec![run[n - 1]];
+        }
+        run.push(pt);
+    }
+    if run.len() >= 2 {
+        polygons.push(offset_polygon(&run, width_meters / 2.0));
+    }
+
+    for p in &mut polygons {
+        p.exterior_mut(|ring| {
+            for c in &mut ring.0 {
+                c.x /= meters_per_degree_lon;
+                c.y /= meters_per_degree_lat;
+            }
+        });
+    }
+    polygons
+}
+
+// Turning by more than 90 degrees
+fn is_sharp_turn(pt1: Coord, pt2: Coord, pt3: Coord) -> bool {
+    let d1 = pt2 - pt1;
+    let d2 = pt3 - pt2;
+    d1.x * d2.x + d1.y * d2.y < 0.0
+}
+
+// Points must be distinct and in meters. Corners are mitred, which stays short because there are
+// no sharp turns.
+fn offset_polygon(pts: &[Coord], half_width: f64) -> Polygon {
+    let normal = |pt1: Coord, pt2: Coord| {
+        let d = pt2 - pt1;
+        let length = (d.x * d.x + d.y * d.y).sqrt();
+        Coord {
+            x: -d.y / length,
+            y: d.x / length,
+        }
+    };
+
+    let mut offsets = Vec::new();
+    for idx in 0..pts.len() {
+        let offset = if idx == 0 {
+            normal(pts[0], pts[1]) * half_width
+        } else if idx == pts.len() - 1 {
+            normal(pts[idx - 1], pts[idx]) * half_width
+        } else {
+            let n1 = normal(pts[idx - 1], pts[idx]);
+            let n2 = normal(pts[idx], pts[idx + 1]);
+            let miter = n1 + n2;
+            let miter_length = (miter.x * miter.x + miter.y * miter.y).sqrt();
+            let miter = miter / miter_length;
+            // Keep the offset sides parallel to both segments
+            miter * (half_width / (miter.x * n1.x + miter.y * n1.y))
+        };
+        offsets.push(offset);
+    }
+
+    let mut ring: Vec<Coord> = pts.iter().zip(&offsets).map(|(pt, o)| *pt + *o).collect();
+    ring.extend(pts.iter().zip(&offsets).rev().map(|(pt, o)| *pt - *o));
+    ring.push(ring[0]);
+    Polygon::new(LineString::new(ring), Vec::new())
+}
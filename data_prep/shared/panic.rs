@@ -0,0 +1,6 @@
+//! Included with `#[path]` by the data_prep tools that call geo's crashy boolean ops.
+
+/// Runs something that might panic, like a boolean op from geo on awkward input
+pub fn catch_panic<T, F: FnOnce() -> T>(f: F) -> Option<T> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).ok()
+}
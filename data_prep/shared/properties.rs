@@ -0,0 +1,35 @@
+//! Reads FlatGeobuf properties into JSON. Included with `#[path]` by the tools that need it.
+
+use geozero::{ColumnValue, PropertyProcessor};
+use serde_json::{Map, Value};
+
+/// Collects every property of a feature, skipping binary ones
+pub struct JsonProperties(pub Map<String, Value>);
+
+impl PropertyProcessor for JsonProperties {
+    fn property(
+        &mut self,
+        _: usize,
+        name: &str,
+        value: &ColumnValue,
+    ) -> geozero::error::Result<bool> {
+        let value = match value {
+            ColumnValue::Byte(x) => Value::from(*x),
+            ColumnValue::UByte(x) => Value::from(*x),
+            ColumnValue::Bool(x) => Value::from(*x),
+            ColumnValue::Short(x) => Value::from(*x),
+            ColumnValue::UShort(x) => Value::from(*x),
+            ColumnValue::Int(x) => Value::from(*x),
+            ColumnValue::UInt(x) => Value::from(*x),
+            ColumnValue::Long(x) => Value::from(*x),
+            ColumnValue::ULong(x) => Value::from(*x),
+            ColumnValue::Float(x) => Value::from(*x),
+            ColumnValue::Double(x) => Value::from(*x),
+            ColumnValue::String(x) | ColumnValue::DateTime(x) => Value::from(*x),
+            ColumnValue::Json(x) => serde_json::from_str(x).unwrap_or_else(|_| Value::from(*x)),
+            ColumnValue::Binary(_) => return Ok(false),
+        };
+        self.0.insert(name.to_string(), value);
+        Ok(false)
+    }
+}
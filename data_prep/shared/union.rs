@@ -0,0 +1,234 @@
+//! Unions polygons, with fallbacks for when geo's boolean ops fail. Included with `#[path]` by the
+//! dissolver and inspire tools, which also need `panic.rs` included as `mod panic`. Both use the
+//! fork of geo with `SpadeBoolops`.
+
+use std::sync::Mutex;
+
+use anyhow::Result;
+use geo::{BooleanOps, Coord, MapCoords, MultiPolygon, Polygon, SpadeBoolops};
+use indicatif::ProgressBar;
+use rstar::{primitives::GeomWithData, ParentNode, RTree, RTreeNode, RTreeObject};
+
+use crate::panic::catch_panic;
+
+/// Snap coordinates to this grid in meters when retrying a failed union
+const SNAP_GRID: f64 = 0.01;
+
+/// Records polygons that couldn't be unioned with their neighbours, even after every fallback.
+/// These are left in the output as they are, possibly overlapping something else.
+#[derive(Default)]
+pub struct Failures {
+    failures: Mutex<Vec<Failure>>,
+    /// Where the polygons currently being unioned came from, when a run has several inputs
+    context: Option<String>,
+}
+
+struct Failure {
+    context: Option<String>,
+    input: Option<usize>,
+    polygon: Polygon,
+}
+
+impl Failures {
+    /// Records later failures as coming from `context`, like the name of an input file
+    pub fn set_context(&mut self, context: String) {
+        self.context = Some(context);
+    }
+
+    fn record(&self, input: Option<usize>, polygon: Polygon) {
+        self.failures.lock().unwrap().push(Failure {
+            context: self.context.clone(),
+            input,
+            polygon,
+        });
+    }
+
+    /// Writes a GeoJSON file with every failed polygon, after transforming them to WGS84 with
+    /// `to_wgs84`. When the polygon is one of the inputs, `input_idx` is the index of the feature
+    /// it came from, counting from 0, within the input named by `context`, if set.
+    pub fn write<F: FnOnce(&mut [Polygon]) -> Result<()>>(
+        self,
+        path: &str,
+        to_wgs84: F,
+    ) -> Result<()> {
+        let failures = self.failures.into_inner().unwrap();
+        if failures.is_empty() {
+            println!("All unions succeeded");
+            return Ok(());
+        }
+        println!(
+            "{} polygons couldn't be unioned, see {path}",
+            failures.len()
+        );
+
+        let mut polygons: Vec<Polygon> = failures.iter().map(|f| f.polygon.clone()).collect();
+        to_wgs84(&mut polygons)?;
+
+        let mut features = Vec::new();
+        for (failure, polygon) in failures.into_iter().zip(polygons) {
+            let mut f = geojson::Feature::from(geojson::Geometry::from(&polygon));
+            if let Some(context) = failure.context {
+                f.set_property("context", context);
+            }
+            if let Some(idx) = failure.input {
+                f.set_property("input_idx", idx);
+            }
+            features.push(f);
+        }
+        let fc = geojson::FeatureCollection {
+            features,
+            bbox: None,
+            foreign_members: None,
+        };
+        std::fs::write(path, serde_json::to_string(&fc)?)?;
+        Ok(())
+    }
+}
+
+/// Unions two multipolygons, trying increasingly slow fallbacks if that fails. If even that
+/// doesn't work, splits each side in half repeatedly to isolate the polygons causing trouble, and
+/// just leaves them un-unioned. `input` should be set when `mp2` is exactly one input polygon,
+/// and is the index of the feature it came from.
+pub fn union(
+    mp1: &MultiPolygon,
+    mp2: &MultiPolygon,
+    input: Option<usize>,
+    failures: &Failures,
+) -> MultiPolygon {
+    let input = input.map(|idx| (idx, &mp2.0[0]));
+    union_bisecting(mp1, mp2, input, failures)
+}
+
+// `input` is the original input polygon, if there is one. It's blamed for any failure, even after
+// splitting up and swapping the two sides.
+fn union_bisecting(
+    mp1: &MultiPolygon,
+    mp2: &MultiPolygon,
+    input: Option<(usize, &Polygon)>,
+    failures: &Failures,
+) -> MultiPolygon {
+    if let Some(result) = try_union(mp1, mp2) {
+        return result;
+    }
+
+    if mp2.0.len() > 1 {
+        let mut left = mp2.0.clone();
+        let right = left.split_off(left.len() / 2);
+        let partial = union_bisecting(mp1, &MultiPolygon::new(left), input, failures);
+        return union_bisecting(&partial, &MultiPolygon::new(right), input, failures);
+    }
+    if mp1.0.len() > 1 {
+        // Bisect the other side instead
+        return union_bisecting(mp2, mp1, input, failures);
+    }
+
+    // Both sides are down to one polygon and they can't be combined
+    match input {
+        Some((idx, polygon)) => failures.record(Some(idx), polygon.clone()),
+        None => {
+            for p in &mp2.0 {
+                failures.record(None, p.clone());
+            }
+        }
+    }
+    let mut polygons = mp1.0.clone();
+    polygons.extend(mp2.0.clone());
+    MultiPolygon::new(polygons)
+}
+
+fn try_union(mp1: &MultiPolygon, mp2: &MultiPolygon) -> Option<MultiPolygon> {
+    // Fast, crashy
+    if let Some(result) = catch_panic(|| mp1.union(mp2)) {
+        return Some(result);
+    }
+
+    // Nearly coincident vertices and edges are usually the problem, so remove them
+    let snapped1 = snap(mp1);
+    let snapped2 = snap(mp2);
+    if let Some(result) = catch_panic(|| snapped1.union(&snapped2)) {
+        return Some(result);
+    }
+
+    // Slow, not crashy
+    catch_panic(|| SpadeBoolops::union(mp1, mp2).ok())?
+}
+
+fn snap(mp: &MultiPolygon) -> MultiPolygon {
+    mp.map_coords(|c| Coord {
+        x: (c.x / SNAP_GRID).round() * SNAP_GRID,
+        y: (c.y / SNAP_GRID).round() * SNAP_GRID,
+    })
+}
+
+/// Unions all the polygons, returning each resulting polygon separately. Takes polygons with the
+/// index of the input feature they came from, if they weren't already unioned with others.
+pub fn cascading_union(
+    polygons: Vec<(Option<usize>, Polygon)>,
+    progress: &ProgressBar,
+    failures: &Failures,
+) -> Vec<Polygon> {
+    let rtree = RTree::bulk_load(
+        polygons
+            .into_iter()
+            .map(|(idx, p)| GeomWithData::new(p, idx))
+            .collect(),
+    );
+
+    // From https://gist.github.com/urschrei/cd80b4d2ec3c75f12fa541a5bdbf6489
+    let init = || MultiPolygon::<f64>::new(vec![]);
+    let fold = |accum: MultiPolygon<f64>,
+                obj: &GeomWithData<Polygon<f64>, Option<usize>>|
+     -> MultiPolygon<f64> {
+        progress.inc(1);
+        // NB the argument to union here is wrong / costly, because it won't accept &Polygon
+        // Perhaps our current union method (which accepts &Self) is too strict?
+        union(
+            &accum,
+            &MultiPolygon::new(vec![obj.geom().clone()]),
+            obj.data,
+            failures,
+        )
+    };
+    let reduce = |accum1: MultiPolygon<f64>, accum2: MultiPolygon<f64>| -> MultiPolygon<f64> {
+        union(&accum1, &accum2, None, failures)
+    };
+
+    bottom_up_fold_reduce(&rtree, init, fold, reduce).0
+}
+
+// From https://gist.github.com/urschrei/cd80b4d2ec3c75f12fa541a5bdbf6489
+// TODO Try the rayon one
+fn bottom_up_fold_reduce<T, S, I, F, R>(
+    tree: &RTree<T>,
+    mut init: I,
+    mut fold: F,
+    mut reduce: R,
+) -> S
+where
+    T: RTreeObject,
+    I: FnMut() -> S,
+    F: FnMut(S, &T) -> S,
+    R: FnMut(S, S) -> S,
+{
+    fn inner<T, S, I, F, R>(parent: &ParentNode<T>, init: &mut I, fold: &mut F, reduce: &mut R) -> S
+    where
+        T: RTreeObject,
+        I: FnMut() -> S,
+        F: FnMut(S, &T) -> S,
+        R: FnMut(S, S) -> S,
+    {
+        parent
+            .children()
+            .iter()
+            .fold(init(), |accum, child| match child {
+                RTreeNode::Leaf(value) => fold(accum, value),
+                RTreeNode::Parent(parent) => {
+                    let value = inner(parent, init, fold, reduce);
+
+                    reduce(accum, value)
+                }
+            })
+    }
+
+    inner(tree.root(), &mut init, &mut fold, &mut reduce)
+}
@@ -0,0 +1,50 @@
+//! Geometry checks shared by the data_prep tools. Each tool includes this file with `#[path]`
+//! instead of depending on a crate, because they use different versions of geo.
+
+use geo::line_intersection::{line_intersection, LineIntersection};
+use geo::{Line, Polygon};
+use rstar::{primitives::GeomWithData, RTree, RTreeObject};
+
+/// Checks if any ring crosses itself or another ring. Segments that only share an endpoint with
+/// their neighbour don't count.
+pub fn self_intersects(p: &Polygon) -> bool {
+    let mut lines: Vec<(usize, Line)> = Vec::new();
+    for (ring, ls) in std::iter::once(p.exterior())
+        .chain(p.interiors())
+        .enumerate()
+    {
+        lines.extend(ls.lines().map(|line| (ring, line)));
+    }
+    let rtree = RTree::bulk_load(
+        lines
+            .iter()
+            .enumerate()
+            .map(|(idx, (_, line))| GeomWithData::new(*line, idx))
+            .collect(),
+    );
+
+    for (idx1, (ring1, line1)) in lines.iter().enumerate() {
+        for obj in rtree.locate_in_envelope_intersecting(&line1.envelope()) {
+            let idx2 = obj.data;
+            if idx1 >= idx2 {
+                continue;
+            }
+            let (ring2, line2) = &lines[idx2];
+            match line_intersection(*line1, *line2) {
+                None => {}
+                Some(LineIntersection::Collinear { .. }) => {
+                    return true;
+                }
+                Some(LineIntersection::SinglePoint { intersection, .. }) => {
+                    // Consecutive segments in one ring always share a point
+                    let shared_endpoint = [line1.start, line1.end].contains(&intersection)
+                        && [line2.start, line2.end].contains(&intersection);
+                    if !(ring1 == ring2 && shared_endpoint) {
+                        return true;
+                    }
+                }
+            }
+        }
+    }
+    false
+}
@@ -0,0 +1,94 @@
+//! A file recording progress through a long run, so it can resume after a crash. The first line
+//! identifies the run, and every following line is one record. Used by the CLI and fix_osmm.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+
+use anyhow::{bail, Result};
+
+pub struct ProgressLog {
+    file: Option<File>,
+}
+
+impl ProgressLog {
+    /// Doesn't record anything
+    pub fn none() -> Self {
+        Self { file: None }
+    }
+
+    /// Starts a new log, replacing any existing file
+    pub fn create(path: &str, header: &str) -> Result<Self> {
+        let mut file = File::create(path)?;
+        file.write_all(format!("{header}\n").as_bytes())?;
+        file.sync_data()?;
+        Ok(Self { file: Some(file) })
+    }
+
+    /// Continues an existing log, returning all of its records. Resuming only makes sense for the
+    /// same input and settings, so `header` must match the one it was created with.
+    ///
+    /// A crash while appending can leave the last line incomplete. That line is discarded and cut
+    /// from the file, so the next record starts on a fresh line.
+    pub fn resume(path: &str, header: &str) -> Result<(Self, Vec<String>)> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        let Some(complete_len) = contents.rfind('\n').map(|idx| idx + 1) else {
+            bail!("{path} doesn't have a complete header line");
+        };
+
+        let mut lines = contents[..complete_len].lines();
+        let first = lines.next().unwrap();
+        if first != header {
+            bail!("{path} is for a different run: {first}, but this run is {header}");
+        }
+        let records = lines.map(|line| line.to_string()).collect();
+
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(complete_len as u64)?;
+        file.sync_data()?;
+        // Append from the truncated end
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok((Self { file: Some(file) }, records))
+    }
+
+    /// Appends one record, which must not contain a newline. Once this returns, the record
+    /// survives a crash.
+    pub fn append(&mut self, record: &str) -> Result<()> {
+        if let Some(ref mut file) = self.file {
+            // One write, so a crash can't interleave a partial record with the next one
+            file.write_all(format!("{record}\n").as_bytes())?;
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incomplete_last_line_is_cut() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("progress_log_{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut log = ProgressLog::create(path, "run")?;
+        log.append("12 100")?;
+        // Simulate a crash partway through the next record
+        OpenOptions::new()
+            .append(true)
+            .open(path)?
+            .write_all(b"13")?;
+
+        let (mut log, records) = ProgressLog::resume(path, "run")?;
+        assert_eq!(records, vec!["12 100"]);
+        log.append("13 200")?;
+
+        let (_, records) = ProgressLog::resume(path, "run")?;
+        assert_eq!(records, vec!["12 100", "13 200"]);
+        assert!(ProgressLog::resume(path, "another run").is_err());
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -15,6 +15,9 @@ export class Backend {
   async getNegativeSpace(
     routeGj: FeatureCollection<LineString>,
     progressCb: (msg: string) => void,
+    highwayBoundariesUrl?: string,
+    sourceUrls: string[] = [],
+    overridesGj?: FeatureCollection<Polygon>,
   ): Promise<FeatureCollection<Polygon>> {
     if (!this.setup) {
       await init();
@@ -22,7 +25,13 @@ export class Backend {
     }
 
     return JSON.parse(
-      await getNegativeSpaceInternal(JSON.stringify(routeGj), progressCb),
+      await getNegativeSpaceInternal(
+        JSON.stringify(routeGj),
+        progressCb,
+        highwayBoundariesUrl,
+        sourceUrls,
+        overridesGj ? JSON.stringify(overridesGj) : undefined,
+      ),
     );
   }
 
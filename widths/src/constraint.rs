@@ -0,0 +1,60 @@
+use geo::Polygon;
+
+/// A polygon limiting how wide a road can be
+pub struct Constraint {
+    pub polygon: Polygon,
+    pub class: Class,
+    /// Identifies the dataset this came from, like an index into a list of input files
+    pub source: usize,
+    /// Identifies the polygon within its source, like an ID column from the input file. Every
+    /// polygon from one MultiPolygon shares the same ID.
+    pub id: Option<String>,
+}
+
+/// What kind of thing a constraint is. Widths are reported separately for each class, so the
+/// order goes from nearest the carriageway outwards.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Class {
+    StreetFurniture,
+    Kerb,
+    Verge,
+    ParcelBoundary,
+    Building,
+    /// Anything unclassified
+    Other,
+}
+
+impl Class {
+    pub const ALL: [Class; 6] = [
+        Class::StreetFurniture,
+        Class::Kerb,
+        Class::Verge,
+        Class::ParcelBoundary,
+        Class::Building,
+        Class::Other,
+    ];
+
+    /// Understands the classes written by `osm_obstacles`, OS MasterMap's `descriptive_group`,
+    /// and the names returned by [`Class::name`]
+    pub fn parse(value: &str) -> Self {
+        match value.to_lowercase().as_str() {
+            "street_furniture" => Class::StreetFurniture,
+            "kerb" | "curb" => Class::Kerb,
+            "verge" | "roadside" => Class::Verge,
+            "parcel" | "wall" | "fence" | "hedge" => Class::ParcelBoundary,
+            "building" => Class::Building,
+            _ => Class::Other,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Class::StreetFurniture => "street_furniture",
+            Class::Kerb => "kerb",
+            Class::Verge => "verge",
+            Class::ParcelBoundary => "parcel",
+            Class::Building => "building",
+            Class::Other => "other",
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use anyhow::{bail, Result};
+use flatgeobuf::{FallibleStreamingIterator, FgbFeature, FgbReader, GeozeroGeometry};
+use geo::Rect;
+use geozero::{ColumnValue, FeatureProperties, PropertyProcessor};
+
+use crate::{Class, Constraint};
+
+/// Reads all constraints from a local FlatGeobuf file overlapping `bbox`
+pub fn read_nearby_polygons(bbox: Rect, path: &str, source: usize) -> Result<Vec<Constraint>> {
+    // TODO Open once?
+    let mut fgb = FgbReader::open(BufReader::new(File::open(path)?))?.select_bbox(
+        bbox.min().x,
+        bbox.min().y,
+        bbox.max().x,
+        bbox.max().y,
+    )?;
+
+    let mut polygons = Vec::new();
+    while let Some(feature) = fgb.next()? {
+        polygons.extend(get_constraints(feature, source)?);
+    }
+    Ok(polygons)
+}
+
+/// Reads all constraints from a remote FlatGeobuf file overlapping `bbox`
+#[cfg(feature = "http")]
+pub async fn read_nearby_polygons_http(
+    bbox: Rect,
+    url: &str,
+    source: usize,
+) -> Result<Vec<Constraint>> {
+    let mut fgb = flatgeobuf::HttpFgbReader::open(url)
+        .await?
+        .select_bbox(bbox.min().x, bbox.min().y, bbox.max().x, bbox.max().y)
+        .await?;
+
+    let mut polygons = Vec::new();
+    while let Some(feature) = fgb.next().await? {
+        polygons.extend(get_constraints(feature, source)?);
+    }
+    Ok(polygons)
+}
+
+// MultiPolygons, like fix_osmm output, become one constraint per polygon. Features without a
+// geometry are skipped.
+fn get_constraints(f: &FgbFeature, source: usize) -> Result<Vec<Constraint>> {
+    let mut p = geozero::geo_types::GeoWriter::new();
+    f.process_geom(&mut p)?;
+    let polygons = match p.take_geometry() {
+        Some(geo::Geometry::Polygon(p)) => vec![p],
+        Some(geo::Geometry::MultiPolygon(mp)) => mp.0,
+        None => return Ok(Vec::new()),
+        _ => bail!("Wrong type in fgb"),
+    };
+    let mut props = ConstraintProperties::default();
+    f.process_properties(&mut props)?;
+    let class = props.class.unwrap_or(Class::Other);
+    Ok(polygons
+        .into_iter()
+        .map(|polygon| Constraint {
+            polygon,
+            class,
+            source,
+            id: props.id.clone(),
+        })
+        .collect())
+}
+
+// Reads the `class` column, or OS MasterMap's `descriptive_group` if that's missing, and the first
+// ID column found
+#[derive(Default)]
+struct ConstraintProperties {
+    class: Option<Class>,
+    found_class_column: bool,
+    id: Option<String>,
+}
+
+impl PropertyProcessor for ConstraintProperties {
+    fn property(
+        &mut self,
+        _: usize,
+        name: &str,
+        value: &ColumnValue,
+    ) -> geozero::error::Result<bool> {
+        match (name, value) {
+            ("class", ColumnValue::String(x)) => {
+                self.class = Some(Class::parse(x));
+                self.found_class_column = true;
+            }
+            ("descriptive_group", ColumnValue::String(x)) if !self.found_class_column => {
+                self.class = Some(Class::parse(x));
+            }
+            ("id" | "fid" | "osm_id" | "toid", _) if self.id.is_none() => {
+                self.id = match value {
+                    ColumnValue::String(x) => Some(x.to_string()),
+                    ColumnValue::Int(x) => Some(x.to_string()),
+                    ColumnValue::UInt(x) => Some(x.to_string()),
+                    ColumnValue::Long(x) => Some(x.to_string()),
+                    ColumnValue::ULong(x) => Some(x.to_string()),
+                    _ => None,
+                };
+            }
+            _ => {}
+        }
+        Ok(false)
+    }
+}
@@ -0,0 +1,133 @@
+use geo::{
+    Coord, Distance, Euclidean, Length, Line, LineInterpolatePoint, LineIntersection, LineString,
+    Point,
+};
+use utils::Mercator;
+
+/// Describes the junctions along a route. Near junctions, perpendiculars tend to shoot down side
+/// streets or get cut by the opposite corner, so they can be flagged, skipped, or trimmed.
+pub struct Junctions {
+    /// Where the route meets other streets, in WGS84
+    pub points: Vec<Point>,
+    /// The other streets meeting the route at those points, in WGS84
+    pub side_streets: Vec<LineString>,
+    /// Perpendiculars starting within this distance of a junction are affected
+    pub distance_meters: f64,
+    /// Skip perpendiculars near junctions entirely, instead of just flagging them
+    pub exclude: bool,
+    /// Cut perpendiculars near junctions short where they cross the mouth of a side street
+    pub trim_at_side_streets: bool,
+    /// Roughly half the width of a carriageway. The mouth of a side street is modelled as a line
+    /// across it this far from the route's centreline, as wide as twice this.
+    pub mouth_meters: f64,
+}
+
+impl Junctions {
+    /// Don't treat any part of the route specially
+    pub fn none() -> Self {
+        Self {
+            points: Vec::new(),
+            side_streets: Vec::new(),
+            distance_meters: 0.0,
+            exclude: false,
+            trim_at_side_streets: false,
+            mouth_meters: 0.0,
+        }
+    }
+}
+
+/// `Junctions` transformed to Mercator
+pub(crate) struct MercatorJunctions<'a> {
+    config: &'a Junctions,
+    points: Vec<Point>,
+    /// A line across the mouth of each side street
+    mouths: Vec<Line>,
+}
+
+impl<'a> MercatorJunctions<'a> {
+    pub fn new(config: &'a Junctions, mercator: &Mercator) -> Self {
+        let points: Vec<Point> = config
+            .points
+            .iter()
+            .map(|pt| mercator.to_mercator(pt))
+            .collect();
+        let mouths = if config.trim_at_side_streets {
+            config
+                .side_streets
+                .iter()
+                .filter_map(|ls| mouth(&mercator.to_mercator(ls), &points, config.mouth_meters))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Self {
+            config,
+            points,
+            mouths,
+        }
+    }
+
+    pub fn is_near(&self, pt: Coord) -> bool {
+        self.points.iter().any(|junction| {
+            Euclidean::distance(*junction, Point::from(pt)) <= self.config.distance_meters
+        })
+    }
+
+    pub fn exclude(&self) -> bool {
+        self.config.exclude
+    }
+
+    /// If the line crosses the mouth of any side street, trims it back to the nearest crossing,
+    /// and also returns the line across that mouth
+    pub fn trim(&self, line: Line) -> (Line, Option<Line>) {
+        let mut result = (line, None);
+        for mouth in &self.mouths {
+            if let Some(LineIntersection::SinglePoint { intersection, .. }) =
+                geo::algorithm::line_intersection::line_intersection(result.0, *mouth)
+            {
+                if intersection != line.start {
+                    result = (Line::new(line.start, intersection), Some(*mouth));
+                }
+            }
+        }
+        result
+    }
+}
+
+// A line across a side street, mouth_meters from the junction along it, and extending
+// mouth_meters to either side. Perpendiculars next to the junction run alongside the side street,
+// so they cross this line where the route's kerb would be.
+fn mouth(side_street: &LineString, junctions: &[Point], mouth_meters: f64) -> Option<Line> {
+    let length = side_street.length::<Euclidean>();
+    if length == 0.0 || mouth_meters <= 0.0 {
+        return None;
+    }
+    // Start from whichever end is at a junction
+    let start = Point::from(side_street.0[0]);
+    let end = Point::from(*side_street.0.last().unwrap());
+    let distance_to_junction = |pt: Point| -> f64 {
+        junctions
+            .iter()
+            .map(|j| Euclidean::distance(*j, pt))
+            .fold(f64::MAX, f64::min)
+    };
+    let (junction, fraction) = if distance_to_junction(start) <= distance_to_junction(end) {
+        (start, (mouth_meters / length).min(1.0))
+    } else {
+        (end, 1.0 - (mouth_meters / length).min(1.0))
+    };
+    let center = side_street.line_interpolate_point(fraction)?;
+
+    let dx = center.x() - junction.x();
+    let dy = center.y() - junction.y();
+    let len = dx.hypot(dy);
+    if len == 0.0 {
+        return None;
+    }
+    // Perpendicular to the side street
+    let offset = Coord {
+        x: -dy / len * mouth_meters,
+        y: dx / len * mouth_meters,
+    };
+    Some(Line::new(center.0 + offset, center.0 - offset))
+}
@@ -1,13 +1,30 @@
+use std::collections::BTreeMap;
+
 use geo::{
-    BoundingRect, Coord, Densify, Destination, Euclidean, Haversine, Length, Line,
+    BoundingRect, Coord, Densify, Destination, Euclidean, Haversine, Intersects, Length, Line,
     LineIntersection, LineString, Point, Polygon, Rect,
 };
 use log::info;
-use rstar::{primitives::GeomWithData, RTree, RTreeObject};
+use rstar::{
+    primitives::{GeomWithData, Rectangle},
+    RTree, RTreeObject, AABB,
+};
 use utils::Mercator;
 
+pub use crate::constraint::{Class, Constraint};
+#[cfg(feature = "fgb")]
+pub use crate::fgb::read_nearby_polygons;
+#[cfg(feature = "http")]
+pub use crate::fgb::read_nearby_polygons_http;
+pub use crate::junctions::Junctions;
+pub use crate::overrides::Overrides;
 pub use crate::timer::Timer;
 
+mod constraint;
+#[cfg(feature = "fgb")]
+mod fgb;
+mod junctions;
+mod overrides;
 mod timer;
 
 pub fn bbox(route_wgs84: &LineString, project_away_meters: f64) -> Rect {
@@ -21,36 +38,111 @@ pub fn bbox(route_wgs84: &LineString, project_away_meters: f64) -> Rect {
     LineString::new(vec![min, max]).bounding_rect().unwrap()
 }
 
+/// Boundaries from different datasets rarely line up exactly, so only flag physical space
+/// extending past adopted land by more than this
+const ADOPTED_TOLERANCE_METERS: f64 = 0.5;
+
+/// What the input polygons represent
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Polygons are obstacles like buildings, and perpendiculars stop at the first one hit
+    NegativeSpace,
+    /// Polygons are road space, like OS MasterMap road fill, and perpendiculars stop where they
+    /// leave the polygons containing the route
+    PositiveSpace,
+}
+
+/// One perpendicular test line across the route
+pub struct Perpendicular {
+    /// Between the nearest constraints on either side, in Mercator
+    pub line: Line,
+    pub width: f64,
+    pub near_junction: bool,
+    /// For every class with a constraint on both sides, the width between the nearest ones, like
+    /// kerb-to-kerb or building-to-building. Empty in positive-space mode.
+    pub class_widths: Vec<(Class, f64)>,
+    /// The width of adopted highway land, if highway boundaries were given and contain this point
+    pub legal_width: Option<f64>,
+    /// True if the physical space on either side extends past the adopted highway boundary
+    pub beyond_adopted: bool,
+    /// The constraints at the start and end of `line`. None where the line was cut at the mouth
+    /// of a side street instead.
+    pub hits: [Option<PolygonHit>; 2],
+}
+
+/// The constraint at one end of a perpendicular
+#[derive(Clone)]
+pub struct PolygonHit {
+    /// The constraint's `source`
+    pub source: usize,
+    /// The constraint's `id`, if it has one
+    pub id: Option<String>,
+    /// The edge of the polygon that was hit, in Mercator
+    pub edge: Line,
+}
+
 pub trait Output {
     fn nearby_polygon(&mut self, mercator: &Mercator, polygon: &Polygon);
-    fn perp_line(&mut self, mercator: &Mercator, line: Line, width: f64);
+    fn perp_line(&mut self, mercator: &Mercator, perp: Perpendicular);
+}
+
+/// Settings for `calculate`
+pub struct Options<'a> {
+    pub mode: Mode,
+    /// Applied to the constraints before anything else
+    pub overrides: &'a Overrides,
+    /// Optional polygons of adopted highway land in WGS84, to compare against the physical width
+    pub highway_boundaries: Vec<Polygon>,
+    pub junctions: &'a Junctions,
+    /// How far apart to test perpendiculars along the route
+    pub step_size_meters: f64,
+    /// How far to look for constraints on either side of the route
+    pub project_away_meters: f64,
 }
 
 // TODO docs
 // everything wgs84 as input
 pub fn calculate<O: Output>(
     route_wgs84: &LineString,
-    mut polygons: Vec<Polygon>,
+    constraints: Vec<Constraint>,
+    options: Options,
     mut timer: Timer,
-    step_size_meters: f64,
-    project_away_meters: f64,
     output: &mut O,
 ) {
+    let Options {
+        mode,
+        overrides,
+        mut highway_boundaries,
+        junctions,
+        step_size_meters,
+        project_away_meters,
+    } = options;
     let mercator = Mercator::from(bbox(route_wgs84, project_away_meters)).unwrap();
+    let junctions = junctions::MercatorJunctions::new(junctions, &mercator);
+
+    let mut constraints = overrides.apply(constraints);
+    for c in &mut constraints {
+        mercator.to_mercator_in_place(&mut c.polygon);
+        output.nearby_polygon(&mercator, &c.polygon);
+    }
+    let mut polygons = Vec::new();
+    let mut classes = Vec::new();
+    let mut sources = Vec::new();
+    let mut ids = Vec::new();
+    for c in constraints {
+        polygons.push(c.polygon);
+        classes.push(c.class);
+        sources.push(c.source);
+        ids.push(c.id);
+    }
 
-    for p in &mut polygons {
+    for p in &mut highway_boundaries {
         mercator.to_mercator_in_place(p);
-        output.nearby_polygon(&mercator, p);
     }
 
     timer.step(format!("Making rtree of {} polygons", polygons.len()));
-    let rtree = RTree::bulk_load(
-        polygons
-            .iter()
-            .enumerate()
-            .map(|(idx, p)| GeomWithData::new(p.clone(), idx))
-            .collect(),
-    );
+    let rtree = make_rtree(&polygons);
+    let boundaries_rtree = make_rtree(&highway_boundaries);
 
     let test_points = points_along_line(&mercator.to_mercator(route_wgs84), step_size_meters);
     let num_test_points = test_points.len();
@@ -69,25 +161,113 @@ pub fn calculate<O: Output>(
             ));
         }
 
-        let mut test_lines = Vec::new();
+        let near_junction = junctions.is_near(pt);
+        if near_junction && junctions.exclude() {
+            continue;
+        }
+        // Parts of the route outside road space can't be measured
+        if mode == Mode::PositiveSpace && !in_any_polygon(pt, &polygons, &rtree) {
+            continue;
+        }
+
+        // For each side, the shortest line hitting each class
+        let mut sides: Vec<BTreeMap<Class, Hit>> = Vec::new();
+        // For each side, where the line stops at a side street, if nothing's hit before
+        let mut mouths = Vec::new();
+        // For each side, where adopted land ends
+        let mut legal_sides = Vec::new();
+        let in_highway = in_any_polygon(pt, &highway_boundaries, &boundaries_rtree);
         for angle_offset in [-90.0, 90.0] {
             let projected = project_away(pt, angle + angle_offset, project_away_meters);
-            let full_line = Line::new(pt, projected);
+            let mut full_line = Line::new(pt, projected);
+            let mut mouth = None;
+            if near_junction {
+                (full_line, mouth) = junctions.trim(full_line);
+            }
+            mouths.push(mouth.map(|edge| Hit {
+                line: full_line,
+                length: full_line.length::<Euclidean>(),
+                polygon: None,
+                edge,
+            }));
 
-            test_lines.extend(shortest_line_hitting_polygon(
-                full_line,
-                &polygons,
-                &rtree,
-                &mut num_hit_checks,
-            ));
+            sides.push(match mode {
+                Mode::NegativeSpace => shortest_lines_hitting_polygons(
+                    full_line,
+                    &polygons,
+                    &classes,
+                    &rtree,
+                    &mut num_hit_checks,
+                ),
+                Mode::PositiveSpace => {
+                    line_leaving_polygons(full_line, &polygons, &rtree, &mut num_hit_checks)
+                        .map(|hit| (Class::Other, hit))
+                        .into_iter()
+                        .collect()
+                }
+            });
+            if in_highway {
+                legal_sides.extend(line_leaving_polygons(
+                    full_line,
+                    &highway_boundaries,
+                    &boundaries_rtree,
+                    &mut num_hit_checks,
+                ));
+            }
         }
         // If either of the test lines doesn't hit anything within project_away_meters, then
         // something's probably wrong -- skip it as output
-        if test_lines.len() != 2 {
+        let (Some(left), Some(right)) = (
+            nearest(&sides[0]).or(mouths[0]),
+            nearest(&sides[1]).or(mouths[1]),
+        ) else {
             continue;
+        };
+        let full_line = Line::new(left.line.end, right.line.end);
+
+        let mut class_widths = Vec::new();
+        if mode == Mode::NegativeSpace {
+            for (class, left) in &sides[0] {
+                if let Some(right) = sides[1].get(class) {
+                    class_widths.push((
+                        *class,
+                        Line::new(left.line.end, right.line.end).length::<Euclidean>(),
+                    ));
+                }
+            }
         }
-        let full_line = Line::new(test_lines[0].end, test_lines[1].end);
-        output.perp_line(&mercator, full_line, full_line.length::<Euclidean>());
+
+        let mut legal_width = None;
+        let mut beyond_adopted = false;
+        if legal_sides.len() == 2 {
+            legal_width = Some(
+                Line::new(legal_sides[0].line.end, legal_sides[1].line.end).length::<Euclidean>(),
+            );
+            for (physical, legal) in [left, right].into_iter().zip(legal_sides) {
+                if physical.length > legal.length + ADOPTED_TOLERANCE_METERS {
+                    beyond_adopted = true;
+                }
+            }
+        }
+
+        output.perp_line(
+            &mercator,
+            Perpendicular {
+                line: full_line,
+                width: full_line.length::<Euclidean>(),
+                near_junction,
+                class_widths,
+                legal_width,
+                beyond_adopted,
+                hits: [left, right].map(|hit| {
+                    hit.polygon.map(|idx| PolygonHit {
+                        source: sources[idx],
+                        id: ids[idx].clone(),
+                        edge: hit.edge,
+                    })
+                }),
+            },
+        );
     }
     timer.pop();
     info!(
@@ -97,6 +277,19 @@ pub fn calculate<O: Output>(
     timer.done();
 }
 
+type PolygonTree = RTree<GeomWithData<Rectangle<Point>, usize>>;
+
+// Callers look up the polygons by index, so only their bounding boxes go in the RTree
+fn make_rtree(polygons: &[Polygon]) -> PolygonTree {
+    RTree::bulk_load(
+        polygons
+            .iter()
+            .enumerate()
+            .map(|(idx, p)| GeomWithData::new(Rectangle::from_aabb(p.envelope()), idx))
+            .collect(),
+    )
+}
+
 // Every step_size along a LineString, returns the point and angle
 fn points_along_line(linestring: &LineString, step_size_meters: f64) -> Vec<(Coord, f64)> {
     let mut result = Vec::new();
@@ -122,15 +315,28 @@ fn project_away(pt: Coord, angle_degrees: f64, distance: f64) -> Coord {
     }
 }
 
+// Where a test line from the route stops
+#[derive(Clone, Copy)]
+struct Hit {
+    line: Line,
+    length: f64,
+    /// Index into the polygons, or None for the mouth of a side street
+    polygon: Option<usize>,
+    /// The polygon edge where the line stops
+    edge: Line,
+}
+
 // Assuming line.start is outside all of the polygons, looks for all possible intersections between
-// the line and a polygon, and trims the line back to the edge of the nearest polygon
-fn shortest_line_hitting_polygon(
+// the line and a polygon, and for each class, trims the line back to the edge of the nearest
+// polygon
+fn shortest_lines_hitting_polygons(
     line: Line,
-    polygons: &Vec<Polygon>,
-    rtree: &RTree<GeomWithData<Polygon, usize>>,
+    polygons: &[Polygon],
+    classes: &[Class],
+    rtree: &PolygonTree,
     num_hit_checks: &mut usize,
-) -> Option<Line> {
-    let mut shortest: Option<(Line, f64)> = None;
+) -> BTreeMap<Class, Hit> {
+    let mut shortest: BTreeMap<Class, Hit> = BTreeMap::new();
     for obj in rtree.locate_in_envelope_intersecting(&line.envelope()) {
         // Ignore polygon holes
         for polygon_line in polygons[obj.data].exterior().lines() {
@@ -141,14 +347,123 @@ fn shortest_line_hitting_polygon(
                 let candidate = Line::new(line.start, intersection);
                 let candidate_length = candidate.length::<Euclidean>();
                 if shortest
-                    .as_ref()
-                    .map(|(_, len)| candidate_length < *len)
+                    .get(&classes[obj.data])
+                    .map(|hit| candidate_length < hit.length)
                     .unwrap_or(true)
                 {
-                    shortest = Some((candidate, candidate_length));
+                    shortest.insert(
+                        classes[obj.data],
+                        Hit {
+                            line: candidate,
+                            length: candidate_length,
+                            polygon: Some(obj.data),
+                            edge: polygon_line,
+                        },
+                    );
+                }
+            }
+        }
+    }
+    shortest
+}
+
+// The shortest hit over all classes
+fn nearest(hits: &BTreeMap<Class, Hit>) -> Option<Hit> {
+    hits.values()
+        .min_by(|a, b| a.length.total_cmp(&b.length))
+        .copied()
+}
+
+// Assuming line.start is inside one of the polygons, trims the line back to the first place where
+// it leaves all of them. Neighbouring polygons sharing an edge count as one space, and holes count
+// as outside.
+fn line_leaving_polygons(
+    line: Line,
+    polygons: &[Polygon],
+    rtree: &PolygonTree,
+    num_hit_checks: &mut usize,
+) -> Option<Hit> {
+    let mut crossings: Vec<(Coord, f64, usize, Line)> = Vec::new();
+    for obj in rtree.locate_in_envelope_intersecting(&line.envelope()) {
+        let polygon = &polygons[obj.data];
+        for ring in std::iter::once(polygon.exterior()).chain(polygon.interiors()) {
+            for polygon_line in ring.lines() {
+                *num_hit_checks += 1;
+                if let Some(LineIntersection::SinglePoint { intersection, .. }) =
+                    geo::algorithm::line_intersection::line_intersection(line, polygon_line)
+                {
+                    let dist = Line::new(line.start, intersection).length::<Euclidean>();
+                    crossings.push((intersection, dist, obj.data, polygon_line));
                 }
             }
         }
     }
-    shortest.map(|pair| pair.0)
+    crossings.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    // After each crossing, check if the line is still inside something
+    for (idx, (pt, length, polygon, edge)) in crossings.iter().enumerate() {
+        let next = crossings
+            .get(idx + 1)
+            .map(|pair| pair.0)
+            .unwrap_or(line.end);
+        let beyond = (*pt + next) / 2.0;
+        if !in_any_polygon(beyond, polygons, rtree) {
+            return Some(Hit {
+                line: Line::new(line.start, *pt),
+                length: *length,
+                polygon: Some(*polygon),
+                edge: *edge,
+            });
+        }
+    }
+    None
+}
+
+fn in_any_polygon(pt: Coord, polygons: &[Polygon], rtree: &PolygonTree) -> bool {
+    rtree
+        .locate_in_envelope_intersecting(&AABB::from_point(pt.into()))
+        .any(|obj| polygons[obj.data].intersects(&pt))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaving(polygons: &[Polygon], line: Line) -> Option<Hit> {
+        line_leaving_polygons(line, polygons, &make_rtree(polygons), &mut 0)
+    }
+
+    #[test]
+    fn test_line_leaving_shared_edge() {
+        // Two squares sharing the edge at x = 10
+        let polygons = vec![
+            Rect::new((0.0, 0.0), (10.0, 10.0)).to_polygon(),
+            Rect::new((10.0, 0.0), (20.0, 10.0)).to_polygon(),
+        ];
+        let hit = leaving(&polygons, Line::new((5.0, 5.0), (50.0, 5.0))).unwrap();
+        assert_eq!(hit.length, 15.0);
+        assert_eq!(hit.polygon, Some(1));
+        assert_eq!(hit.line.end, Coord { x: 20.0, y: 5.0 });
+
+        // Never leaving
+        assert!(leaving(&polygons, Line::new((5.0, 5.0), (15.0, 5.0))).is_none());
+    }
+
+    #[test]
+    fn test_line_leaving_into_hole() {
+        let polygons = vec![Polygon::new(
+            Rect::new((0.0, 0.0), (30.0, 30.0))
+                .to_polygon()
+                .exterior()
+                .clone(),
+            vec![Rect::new((10.0, 10.0), (20.0, 20.0))
+                .to_polygon()
+                .exterior()
+                .clone()],
+        )];
+        let hit = leaving(&polygons, Line::new((5.0, 15.0), (50.0, 15.0))).unwrap();
+        assert_eq!(hit.length, 5.0);
+        assert_eq!(hit.edge.start.x, 10.0);
+        assert_eq!(hit.edge.end.x, 10.0);
+    }
 }
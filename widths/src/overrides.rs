@@ -0,0 +1,75 @@
+use geo::{Contains, InteriorPoint, Polygon};
+
+use crate::Constraint;
+
+/// Manual fixes to the constraint data, like obstacles that no longer exist or are missing
+#[derive(Default)]
+pub struct Overrides {
+    /// Constraints with an interior point inside one of these polygons are removed. In WGS84.
+    pub remove: Vec<Polygon>,
+    /// Extra constraints, in WGS84
+    pub add: Vec<Constraint>,
+}
+
+impl Overrides {
+    /// Removes constraints first, then appends the additions, so added obstacles are never
+    /// removed. The order of everything is preserved, so the result only depends on the inputs.
+    pub fn apply(&self, constraints: Vec<Constraint>) -> Vec<Constraint> {
+        let mut result: Vec<Constraint> = constraints
+            .into_iter()
+            .filter(|c| {
+                // Checking one point, instead of any overlap, avoids removing neighbours that a
+                // hand-drawn polygon clips slightly
+                let Some(pt) = c.polygon.interior_point() else {
+                    return true;
+                };
+                !self.remove.iter().any(|remove| remove.contains(&pt))
+            })
+            .collect();
+        result.extend(self.add.iter().map(|c| Constraint {
+            polygon: c.polygon.clone(),
+            class: c.class,
+            source: c.source,
+            id: c.id.clone(),
+        }));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::{BoundingRect, Rect};
+
+    use super::*;
+    use crate::Class;
+
+    fn constraint(x: f64, source: usize) -> Constraint {
+        Constraint {
+            polygon: Rect::new((x, 0.0), (x + 1.0, 1.0)).to_polygon(),
+            class: Class::Building,
+            source,
+            id: None,
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let overrides = Overrides {
+            // Covers the middle of the second constraint and clips the edge of the third
+            remove: vec![Rect::new((9.0, -1.0), (20.2, 2.0)).to_polygon()],
+            // Inside the removed area, but additions are never removed
+            add: vec![constraint(15.0, 1)],
+        };
+        let result = overrides.apply(vec![
+            constraint(0.0, 0),
+            constraint(10.0, 0),
+            constraint(20.0, 0),
+        ]);
+
+        let xs: Vec<(f64, usize)> = result
+            .iter()
+            .map(|c| (c.polygon.bounding_rect().unwrap().min().x, c.source))
+            .collect();
+        assert_eq!(xs, vec![(0.0, 0), (20.0, 0), (15.0, 1)]);
+    }
+}